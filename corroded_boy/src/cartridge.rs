@@ -0,0 +1,391 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::mbc::{NoMBC, MBC, MBC1, MBC2, MBC2_RAM_SIZE, MBC3, MBC5};
+use crate::rtc::{Clock, SystemClock};
+
+const HEADER_END: usize = 0x150;
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    Truncated { expected: usize, actual: usize },
+    RomSizeMismatch { expected: usize, actual: usize },
+    UnsupportedCartridgeType(u8),
+    InvalidRomSize(u8),
+    InvalidRamSize(u8),
+    HeaderChecksum { expected: u8, actual: u8 },
+    GlobalChecksum { expected: u16, actual: u16 },
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Io(err) => write!(f, "could not read rom: {}", err),
+            CartridgeError::Truncated { expected, actual } => write!(
+                f,
+                "rom is truncated: expected at least {:#X} bytes, found {:#X}",
+                expected, actual
+            ),
+            CartridgeError::RomSizeMismatch { expected, actual } => write!(
+                f,
+                "header declares {:#X} bytes of rom, file has {:#X}",
+                expected, actual
+            ),
+            CartridgeError::UnsupportedCartridgeType(code) => {
+                write!(f, "unsupported cartridge type {:#04X}", code)
+            }
+            CartridgeError::InvalidRomSize(code) => {
+                write!(f, "invalid rom size code {:#04X}", code)
+            }
+            CartridgeError::InvalidRamSize(code) => {
+                write!(f, "invalid ram size code {:#04X}", code)
+            }
+            CartridgeError::HeaderChecksum { expected, actual } => write!(
+                f,
+                "header checksum mismatch: header says {:#04X}, computed {:#04X}",
+                expected, actual
+            ),
+            CartridgeError::GlobalChecksum { expected, actual } => write!(
+                f,
+                "global checksum mismatch: header says {:#06X}, computed {:#06X}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+impl From<io::Error> for CartridgeError {
+    fn from(err: io::Error) -> CartridgeError {
+        CartridgeError::Io(err)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CgbFlag {
+    DmgOnly,
+    CgbCompatible, //0x80, runs on both
+    CgbOnly,       //0xC0
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MbcKind {
+    None,
+    MBC1,
+    MBC2,
+    MBC3,
+    MBC5,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct CartridgeType {
+    pub code: u8,
+    pub mbc: MbcKind,
+    #[allow(dead_code)] //parsed for completeness, the ram size decides the allocation
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+}
+
+impl CartridgeType {
+    pub fn from_code(code: u8) -> Result<CartridgeType, CartridgeError> {
+        let (mbc, ram, battery, timer, rumble) = match code {
+            0x00 => (MbcKind::None, false, false, false, false),
+            0x01 => (MbcKind::MBC1, false, false, false, false),
+            0x02 => (MbcKind::MBC1, true, false, false, false),
+            0x03 => (MbcKind::MBC1, true, true, false, false),
+            0x05 => (MbcKind::MBC2, false, false, false, false),
+            0x06 => (MbcKind::MBC2, false, true, false, false),
+            0x08 => (MbcKind::None, true, false, false, false),
+            0x09 => (MbcKind::None, true, true, false, false),
+            0x0F => (MbcKind::MBC3, false, true, true, false),
+            0x10 => (MbcKind::MBC3, true, true, true, false),
+            0x11 => (MbcKind::MBC3, false, false, false, false),
+            0x12 => (MbcKind::MBC3, true, false, false, false),
+            0x13 => (MbcKind::MBC3, true, true, false, false),
+            0x19 => (MbcKind::MBC5, false, false, false, false),
+            0x1A => (MbcKind::MBC5, true, false, false, false),
+            0x1B => (MbcKind::MBC5, true, true, false, false),
+            0x1C => (MbcKind::MBC5, false, false, false, true),
+            0x1D => (MbcKind::MBC5, true, false, false, true),
+            0x1E => (MbcKind::MBC5, true, true, false, true),
+            _ => return Err(CartridgeError::UnsupportedCartridgeType(code)),
+        };
+        Ok(CartridgeType {
+            code,
+            mbc,
+            ram,
+            battery,
+            timer,
+            rumble,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Licensee {
+    Old(u8),
+    New(String), //two ascii characters, used when the old code is 0x33
+}
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub title: String,
+    pub cgb_flag: CgbFlag,
+    #[allow(dead_code)] //no sgb support, kept for frontends
+    pub sgb: bool,
+    pub cartridge_type: CartridgeType,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    pub licensee: Licensee,
+    #[allow(dead_code)] //header info for frontends
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl Header {
+    pub fn parse(rom: &[u8]) -> Result<Header, CartridgeError> {
+        if rom.len() < HEADER_END {
+            return Err(CartridgeError::Truncated {
+                expected: HEADER_END,
+                actual: rom.len(),
+            });
+        }
+
+        let cgb_flag = match rom[0x143] {
+            0xC0 => CgbFlag::CgbOnly,
+            byte if byte & 0x80 != 0 => CgbFlag::CgbCompatible,
+            _ => CgbFlag::DmgOnly,
+        };
+        //newer carts reuse the end of the title area for the manufacturer code and cgb flag
+        let title_end = if cgb_flag == CgbFlag::DmgOnly {
+            0x144
+        } else {
+            0x13F
+        };
+        let title = rom[0x134..title_end]
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| byte as char)
+            .collect::<String>();
+
+        let rom_size = match rom[0x148] {
+            code @ 0x00..=0x08 => 0x8000 << code,
+            code => return Err(CartridgeError::InvalidRomSize(code)),
+        };
+        let ram_size = match rom[0x149] {
+            0x00 => 0,
+            0x01 => 0x800,
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x20000,
+            0x05 => 0x10000,
+            code => return Err(CartridgeError::InvalidRamSize(code)),
+        };
+        let licensee = match rom[0x14B] {
+            0x33 => Licensee::New(rom[0x144..=0x145].iter().map(|&b| b as char).collect()),
+            code => Licensee::Old(code),
+        };
+
+        Ok(Header {
+            title,
+            cgb_flag,
+            sgb: rom[0x146] == 0x03,
+            cartridge_type: CartridgeType::from_code(rom[0x147])?,
+            rom_size,
+            ram_size,
+            licensee,
+            version: rom[0x14C],
+            header_checksum: rom[0x14D],
+            global_checksum: (rom[0x14E] as u16) << 8 | rom[0x14F] as u16,
+        })
+    }
+}
+
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[0x134..=0x14C]
+        .iter()
+        .fold(0u8, |acc, &byte| acc.wrapping_sub(byte).wrapping_sub(1))
+}
+
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 0x14E && i != 0x14F)
+        .fold(0u16, |acc, (_, &byte)| acc.wrapping_add(byte as u16))
+}
+
+pub struct Cartridge {
+    pub header: Header,
+    mbc: Box<dyn MBC>,
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    save_path: Option<PathBuf>,
+    save_dirty: bool,
+    warnings: Vec<CartridgeError>, //header problems that do not stop the rom from running
+}
+
+impl Cartridge {
+    //Battery backed carts are bound to a .sav file next to the rom, which is loaded if it already exists
+    pub fn from_file(path: &Path) -> Result<Cartridge, CartridgeError> {
+        let mut cartridge = Cartridge::from_bytes(fs::read(path)?)?;
+        if cartridge.header.cartridge_type.battery {
+            let save_path = path.with_extension("sav");
+            match fs::read(&save_path) {
+                Ok(data) => cartridge.load_save_data(&data),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(CartridgeError::Io(err)),
+            }
+            cartridge.save_path = Some(save_path);
+        }
+        Ok(cartridge)
+    }
+
+    pub fn from_bytes(rom: Vec<u8>) -> Result<Cartridge, CartridgeError> {
+        Cartridge::from_bytes_with_clock(rom, Box::new(SystemClock))
+    }
+
+    //The clock only drives the rtc of mbc3 carts with a timer, other carts ignore it
+    pub fn from_bytes_with_clock(
+        rom: Vec<u8>,
+        clock: Box<dyn Clock>,
+    ) -> Result<Cartridge, CartridgeError> {
+        let header = Header::parse(&rom)?;
+
+        if rom.len() < header.rom_size {
+            return Err(CartridgeError::Truncated {
+                expected: header.rom_size,
+                actual: rom.len(),
+            });
+        }
+        //hardware never looks at the rom size or the global checksum, many homebrew and patched
+        //roms get them wrong but run fine
+        let mut warnings = Vec::new();
+        if rom.len() > header.rom_size {
+            warnings.push(CartridgeError::RomSizeMismatch {
+                expected: header.rom_size,
+                actual: rom.len(),
+            });
+        }
+
+        let checksum = header_checksum(&rom);
+        if checksum != header.header_checksum {
+            return Err(CartridgeError::HeaderChecksum {
+                expected: header.header_checksum,
+                actual: checksum,
+            });
+        }
+        let checksum = global_checksum(&rom);
+        if checksum != header.global_checksum {
+            warnings.push(CartridgeError::GlobalChecksum {
+                expected: header.global_checksum,
+                actual: checksum,
+            });
+        }
+
+        let mbc: Box<dyn MBC> = match header.cartridge_type.mbc {
+            MbcKind::None => Box::new(NoMBC),
+            MbcKind::MBC1 => Box::new(MBC1::new(&rom)),
+            MbcKind::MBC3 if header.cartridge_type.timer => Box::new(MBC3::new(Some(clock))),
+            MbcKind::MBC3 => Box::new(MBC3::new(None)),
+            MbcKind::MBC2 => Box::new(MBC2::new()),
+            MbcKind::MBC5 => Box::new(MBC5::new(header.cartridge_type.rumble)),
+        };
+        //mbc2 has its ram built in, the header always declares none
+        let ram_size = match header.cartridge_type.mbc {
+            MbcKind::MBC2 => MBC2_RAM_SIZE,
+            _ => header.ram_size,
+        };
+        let ram = vec![0; ram_size];
+        Ok(Cartridge {
+            header,
+            mbc,
+            rom,
+            ram,
+            save_path: None,
+            save_dirty: false,
+            warnings,
+        })
+    }
+
+    //Contents of the external ram followed by any mbc state, e.g. the 48 byte rtc trailer
+    pub fn save_data(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        data.extend(self.mbc.save_state());
+        data
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        let len = self.ram.len().min(data.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+        if data.len() > self.ram.len() {
+            self.mbc.load_state(&data[self.ram.len()..]);
+        }
+    }
+
+    //Raw title area including the cgb flag, hashed by the cgb boot rom to colourise dmg games
+    pub fn title_bytes(&self) -> &[u8] {
+        &self.rom[0x134..=0x143]
+    }
+
+    pub fn warnings(&self) -> &[CartridgeError] {
+        &self.warnings
+    }
+
+    pub fn save_path(&self) -> Option<&Path> {
+        self.save_path.as_deref()
+    }
+
+    //Writes the save file if anything in the ram area was written since the last flush
+    pub fn flush_save(&mut self) -> io::Result<()> {
+        let path = match &self.save_path {
+            Some(path) if self.save_dirty => path,
+            _ => return Ok(()),
+        };
+        //write to a temporary file first so a crash mid write does not destroy the old save
+        let tmp_path = path.with_extension("sav.tmp");
+        fs::write(&tmp_path, self.save_data())?;
+        fs::rename(&tmp_path, path)?;
+        self.save_dirty = false;
+        Ok(())
+    }
+
+    #[allow(dead_code)] //motor state of mbc5 rumble carts, the cli has nothing to vibrate
+    pub fn rumble(&self) -> bool {
+        self.mbc.rumble()
+    }
+
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x7FFF => self.mbc.read_rom(&self.rom, addr),
+            0xA000..=0xBFFF => self.mbc.read_ram(&self.ram, addr),
+            _ => panic!("Not a valid cartridge memory area"),
+        }
+    }
+
+    pub fn write_byte(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x7FFF => self.mbc.write_rom(addr, value),
+            0xA000..=0xBFFF => {
+                if self.mbc.write_ram(&mut self.ram, addr, value) {
+                    self.save_dirty = true;
+                }
+            }
+            _ => panic!("Not a valid cartridge memory area"),
+        }
+    }
+}
+
+impl Drop for Cartridge {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush_save() {
+            eprintln!("could not write save file: {}", err);
+        }
+    }
+}
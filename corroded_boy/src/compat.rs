@@ -0,0 +1,235 @@
+use crate::cartridge::Licensee;
+use crate::ppu::ppu;
+use std::str::FromStr;
+
+//Palette data of the cgb boot rom, 4 rgb555 colors per palette, lightest first
+const PALETTES: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000, //0
+    0x639F, 0x4279, 0x15B0, 0x04CB, //1
+    0x7FFF, 0x6E31, 0x454A, 0x0000, //2
+    0x7FFF, 0x1BEF, 0x0200, 0x0000, //3
+    0x7FFF, 0x421F, 0x1CF2, 0x0000, //4
+    0x7FFF, 0x5294, 0x294A, 0x0000, //5
+    0x7FFF, 0x03FF, 0x012F, 0x0000, //6
+    0x7FFF, 0x03EF, 0x01D6, 0x0000, //7
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000, //8
+    0x7E74, 0x03FF, 0x0180, 0x0000, //9
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B, //10
+    0x7ED6, 0x4BFF, 0x2175, 0x0000, //11
+    0x53FF, 0x4A5F, 0x7E52, 0x0000, //12
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0, //13
+    0x03ED, 0x7FFF, 0x255F, 0x0000, //14
+    0x036A, 0x021F, 0x03FF, 0x7FFF, //15
+    0x7FFF, 0x01DF, 0x0112, 0x0000, //16
+    0x231F, 0x035F, 0x00F2, 0x0009, //17
+    0x7FFF, 0x03EA, 0x011F, 0x0000, //18
+    0x299F, 0x001A, 0x000C, 0x0000, //19
+    0x7FFF, 0x027F, 0x001F, 0x0000, //20
+    0x7FFF, 0x03E0, 0x0206, 0x0120, //21
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00, //22
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F, //23
+    0x7FFF, 0x03FF, 0x001F, 0x0000, //24
+    0x03FF, 0x001F, 0x000C, 0x0000, //25
+    0x7FFF, 0x033F, 0x0193, 0x0000, //26
+    0x0000, 0x4200, 0x037F, 0x7FFF, //27
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000, //28
+    0x7FFF, 0x1BEF, 0x6180, 0x0000, //29
+];
+
+//Offsets into PALETTES of the obj0, obj1 and bg palette. A few entries are not aligned to a
+//palette and pick up the last color of the previous one, the boot rom does the same
+const COMBINATIONS: [[usize; 3]; 51] = [
+    [4 * 4, 4 * 4, 29 * 4],   //0, right + a
+    [18 * 4, 18 * 4, 18 * 4], //1, right
+    [20 * 4, 20 * 4, 20 * 4],
+    [24 * 4, 24 * 4, 24 * 4], //3, down + a
+    [9 * 4, 9 * 4, 9 * 4],
+    [0, 0, 0],                //5, up
+    [27 * 4, 27 * 4, 27 * 4], //6, right + b
+    [5 * 4, 5 * 4, 5 * 4],    //7, left + b
+    [12 * 4, 12 * 4, 12 * 4], //8, down
+    [26 * 4, 26 * 4, 26 * 4],
+    [16 * 4, 8 * 4, 8 * 4],
+    [4 * 4, 28 * 4, 28 * 4],
+    [4 * 4, 2 * 4, 2 * 4],
+    [3 * 4, 4 * 4, 4 * 4],
+    [4 * 4, 29 * 4, 29 * 4],
+    [28 * 4, 4 * 4, 28 * 4],
+    [2 * 4, 17 * 4, 2 * 4],
+    [16 * 4, 16 * 4, 8 * 4],
+    [4 * 4, 4 * 4, 7 * 4],
+    [4 * 4, 4 * 4, 18 * 4],
+    [4 * 4, 4 * 4, 20 * 4],
+    [19 * 4, 19 * 4, 9 * 4],
+    [4 * 4 - 1, 4 * 4 - 1, 11 * 4],
+    [17 * 4, 17 * 4, 2 * 4],
+    [4 * 4, 4 * 4, 2 * 4],
+    [4 * 4, 4 * 4, 3 * 4],
+    [28 * 4, 28 * 4, 0],
+    [3 * 4, 3 * 4, 0],
+    [0, 0, 4], //28, up + b
+    [18 * 4, 22 * 4, 18 * 4],
+    [20 * 4, 22 * 4, 20 * 4],
+    [24 * 4, 22 * 4, 24 * 4],
+    [16 * 4, 22 * 4, 8 * 4],
+    [17 * 4, 4 * 4, 13 * 4],
+    [28 * 4 - 1, 0, 14 * 4],
+    [28 * 4 - 1, 4 * 4, 15 * 4],
+    [19 * 4, 22 * 4, 9 * 4],
+    [16 * 4, 28 * 4, 10 * 4],
+    [4 * 4, 23 * 4, 28 * 4],
+    [17 * 4, 22 * 4, 2 * 4],
+    [4 * 4, 0, 2 * 4], //40, left + a
+    [4 * 4, 28 * 4, 3 * 4],
+    [28 * 4, 3 * 4, 0],
+    [3 * 4, 28 * 4, 4 * 4], //43, up + a
+    [21 * 4, 28 * 4, 4 * 4],
+    [3 * 4, 28 * 4, 0],
+    [25 * 4, 3 * 4, 28 * 4],
+    [0, 28 * 4, 8 * 4],
+    [4 * 4, 3 * 4, 28 * 4], //48, left
+    [28 * 4, 3 * 4, 6 * 4], //49, down + b
+    [4 * 4, 28 * 4, 29 * 4],
+];
+
+//Sums of the 16 title bytes of known nintendo games, the last 14 are shared by several titles
+const TITLE_CHECKSUMS: [u8; 79] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B, 0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+];
+
+const FIRST_SHARED_CHECKSUM: usize = 65;
+const SHARED_CHECKSUMS: usize = TITLE_CHECKSUMS.len() - FIRST_SHARED_CHECKSUM;
+
+//Fourth title letter of the games with a shared checksum, in rows of SHARED_CHECKSUMS
+const FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+//Combination used by each title, first by checksum and then by fourth letter
+const TITLE_COMBINATIONS: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20, 5, 33, 13, 14, 5, 29,
+    5, 18, 9, 3, 2, 26, 25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34, 5, 42, 6,
+    5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0, 39, 36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39,
+    24, 31, 50, 17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+//Direction and button held while the boot logo is shown, picks a fixed palette instead
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PaletteOverride {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl PaletteOverride {
+    fn combination(self) -> usize {
+        match self {
+            PaletteOverride::Up => 5,
+            PaletteOverride::UpA => 43,
+            PaletteOverride::UpB => 28,
+            PaletteOverride::Left => 48,
+            PaletteOverride::LeftA => 40,
+            PaletteOverride::LeftB => 7,
+            PaletteOverride::Down => 8,
+            PaletteOverride::DownA => 3,
+            PaletteOverride::DownB => 49,
+            PaletteOverride::Right => 1,
+            PaletteOverride::RightA => 0,
+            PaletteOverride::RightB => 6,
+        }
+    }
+}
+
+//Accepts a direction optionally followed by +a or +b, e.g. "left+b"
+impl FromStr for PaletteOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PaletteOverride, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "up" => Ok(PaletteOverride::Up),
+            "up+a" => Ok(PaletteOverride::UpA),
+            "up+b" => Ok(PaletteOverride::UpB),
+            "left" => Ok(PaletteOverride::Left),
+            "left+a" => Ok(PaletteOverride::LeftA),
+            "left+b" => Ok(PaletteOverride::LeftB),
+            "down" => Ok(PaletteOverride::Down),
+            "down+a" => Ok(PaletteOverride::DownA),
+            "down+b" => Ok(PaletteOverride::DownB),
+            "right" => Ok(PaletteOverride::Right),
+            "right+a" => Ok(PaletteOverride::RightA),
+            "right+b" => Ok(PaletteOverride::RightB),
+            _ => Err(format!(
+                "expected a direction with optional +a/+b, got {}",
+                s
+            )),
+        }
+    }
+}
+
+//0xRRGGBB colors the cgb boot rom loads for a dmg game, indexed by the BGP/OBP shades
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct CompatPalettes {
+    pub bg: [u32; 4],
+    pub obj: [[u32; 4]; 2],
+}
+
+impl CompatPalettes {
+    fn from_combination(combination: usize) -> CompatPalettes {
+        let [obj0, obj1, bg] = COMBINATIONS[combination];
+        CompatPalettes {
+            bg: colors(bg),
+            obj: [colors(obj0), colors(obj1)],
+        }
+    }
+
+    //Only games licensed by nintendo are looked up by title, everything else gets the default
+    pub fn for_title(licensee: &Licensee, title: &[u8]) -> CompatPalettes {
+        let nintendo = match licensee {
+            Licensee::Old(code) => *code == 0x01,
+            Licensee::New(code) => code == "01",
+        };
+        let index = if nintendo { title_index(title) } else { 0 };
+        CompatPalettes::from_combination(TITLE_COMBINATIONS[index] as usize)
+    }
+
+    pub fn for_override(palette_override: PaletteOverride) -> CompatPalettes {
+        CompatPalettes::from_combination(palette_override.combination())
+    }
+}
+
+//Index into TITLE_COMBINATIONS, 0 for unknown titles
+fn title_index(title: &[u8]) -> usize {
+    let checksum = title.iter().fold(0u8, |acc, &byte| acc.wrapping_add(byte));
+    let index = match TITLE_CHECKSUMS.iter().position(|&sum| sum == checksum) {
+        Some(index) => index,
+        None => return 0,
+    };
+    if index < FIRST_SHARED_CHECKSUM {
+        return index;
+    }
+    //shared checksums are told apart by the fourth letter of the title
+    let fourth = title.get(3).copied().unwrap_or(0);
+    (index - FIRST_SHARED_CHECKSUM..FOURTH_LETTERS.len())
+        .step_by(SHARED_CHECKSUMS)
+        .find(|&i| FOURTH_LETTERS[i] == fourth)
+        .map_or(0, |i| FIRST_SHARED_CHECKSUM + i)
+}
+
+fn colors(offset: usize) -> [u32; 4] {
+    let mut colors = [0; 4];
+    for (i, color) in colors.iter_mut().enumerate() {
+        *color = ppu::rgb555(PALETTES[offset + i]);
+    }
+    colors
+}
@@ -1,22 +1,24 @@
-use crate::memory::Memory;
+use crate::cartridge::Cartridge;
+use crate::interrupt::Interrupt;
+use crate::memory::{Memory, Model};
 use crate::register::Flags::{FC, FH, FN, FZ};
 use crate::register::RegisterFile;
 use crate::register::Registers16b::{AF, BC, DE, HL, SP};
-use crate::register::Registers8b::{A, B, C, D, E, F, H, L};
+use crate::register::Registers8b::{A, B, C, D, E, H, L};
 use crate::register::{Registers16b, Registers8b};
 
-const instr_cycles: [u8; 256] = [
-    1, 3, 2, 2, 1, 1, 2, 1, 4, 2, 2, 2, 1, 1, 2, 1, 1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1,
+const INSTR_CYCLES: [u8; 256] = [
+    1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1, 1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1,
     2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1, 2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1,
     1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
     1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, 2, 2, 2, 2, 2, 2, 1, 2, 1, 1, 1, 1, 1, 1, 2, 1,
     1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
     1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
-    2, 3, 3, 4, 3, 4, 2, 4, 2, 4, 3, 1, 3, 6, 2, 4, 2, 3, 3, 0, 3, 4, 2, 4, 2, 4, 3, 0, 3, 0, 2, 4,
-    3, 3, 2, 0, 0, 4, 2, 4, 4, 1, 4, 0, 0, 0, 2, 4, 3, 3, 2, 1, 0, 4, 2, 4, 3, 2, 4, 1, 0, 0, 2, 4,
+    2, 3, 3, 4, 3, 4, 2, 4, 2, 4, 3, 1, 3, 6, 2, 4, 2, 3, 3, 1, 3, 4, 2, 4, 2, 4, 3, 1, 3, 1, 2, 4,
+    3, 3, 2, 1, 1, 4, 2, 4, 4, 1, 4, 1, 1, 1, 2, 4, 3, 3, 2, 1, 1, 4, 2, 4, 3, 2, 4, 1, 1, 1, 2, 4,
 ];
 
-const cb_instr_cycles: [u8; 256] = [
+const CB_INSTR_CYCLES: [u8; 256] = [
     2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
     2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
     2, 2, 2, 2, 2, 2, 3, 2, 2, 2, 2, 2, 2, 2, 3, 2, 2, 2, 2, 2, 2, 2, 3, 2, 2, 2, 2, 2, 2, 2, 3, 2,
@@ -26,11 +28,31 @@ const cb_instr_cycles: [u8; 256] = [
     2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
     2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
 ];
+
+const SPEED_SWITCH_CYCLES: u32 = 2050;
+
+//Extra M-cycles a conditional jr/jp/call/ret takes when the branch is taken
+fn branch_cycles(operation: u8) -> u8 {
+    match operation {
+        0x20 | 0x28 | 0x30 | 0x38 => 1,
+        0xC2 | 0xCA | 0xD2 | 0xDA => 1,
+        0xC0 | 0xC8 | 0xD0 | 0xD8 => 3,
+        0xC4 | 0xCC | 0xD4 | 0xDC => 3,
+        _ => 0,
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     pub reg: RegisterFile,
     pub mem: Memory,
     pub is_halted: bool,
+    pub is_stopped: bool,
+    pub is_locked: bool, //set by illegal opcodes, the cpu hangs while the rest keeps running
     pub ime: bool,
+    ime_pending: bool, //EI only takes effect after the next instruction
+    halt_bug: bool,    //next opcode fetch does not increment pc
+    stop_lines: u8,    //joypad input lines that were already low in stop mode
 }
 
 impl CPU {
@@ -39,12 +61,100 @@ impl CPU {
             reg: RegisterFile::new(),
             mem: Memory::new(),
             is_halted: false,
+            is_stopped: false,
+            is_locked: false,
             ime: false,
+            ime_pending: false,
+            halt_bug: false,
+            stop_lines: 0,
+        }
+    }
+
+    //Inserts the cartridge and sets the registers the boot rom leaves behind, execution starts
+    //at the cartridge entry point
+    pub fn load_cartridge(&mut self, cartridge: Cartridge) {
+        self.mem.load_cartridge(cartridge);
+        let (af, bc, de, hl) = match self.mem.model() {
+            Some(Model::Cgb) if self.mem.cgb_mode() => (0x1180, 0x0000, 0xFF56, 0x000D),
+            Some(Model::Cgb) => (0x1180, 0x0000, 0x0008, 0x007C), //running a dmg game
+            _ => (0x01B0, 0x0013, 0x00D8, 0x014D),
+        };
+        self.reg.write_16b(AF, af);
+        self.reg.write_16b(BC, bc);
+        self.reg.write_16b(DE, de);
+        self.reg.write_16b(HL, hl);
+        self.reg.sp = 0xFFFE;
+        self.reg.pc = 0x0100;
+    }
+
+    //Runs one instruction, interrupt dispatch or idle cycle and advances the rest of the system alongside
+    pub fn step(&mut self) -> u32 {
+        let mut cycles = self.run();
+        //timer and ppu are frozen in stop mode
+        if !self.is_stopped {
+            self.mem.step(cycles);
+        }
+        //the cpu sits idle while hdma copies, the rest of the system keeps running
+        loop {
+            let stall = self.mem.take_stall();
+            if stall == 0 {
+                break;
+            }
+            self.mem.step(stall);
+            cycles += stall;
+        }
+        cycles
+    }
+
+    fn run(&mut self) -> u32 {
+        if self.is_locked {
+            return 1;
+        }
+        if self.ime && self.mem.pending_interrupts() != 0 {
+            return self.dispatch_interrupt();
+        }
+        //stop mode is only left by a selected joypad line going low, independent of ie, ime and IF
+        if self.is_stopped {
+            let lines = self.mem.joypad.lines();
+            let woken = lines & !self.stop_lines != 0;
+            self.stop_lines = lines;
+            if !woken {
+                return 1;
+            }
+            self.is_stopped = false;
+        }
+        //a halted cpu wakes up on any pending interrupt, even if ime is not set
+        if self.is_halted {
+            if self.mem.pending_interrupts() == 0 {
+                return 1;
+            }
+            self.is_halted = false;
         }
+        if self.ime_pending {
+            self.ime_pending = false;
+            self.ime = true;
+        }
+        self.execute()
+    }
+
+    //Jumps to the vector of the highest priority pending interrupt, takes 5 M-cycles
+    fn dispatch_interrupt(&mut self) -> u32 {
+        let int = match Interrupt::highest_priority(self.mem.pending_interrupts()) {
+            Some(int) => int,
+            None => return 0,
+        };
+        self.ime = false;
+        self.is_halted = false;
+        self.mem.clear_interrupt(int);
+        self.push_stack(self.reg.pc);
+        self.reg.pc = int.vector();
+        5
     }
 
-    fn execute(&mut self) {
+    //Executes one instruction and returns the number of M-cycles it took
+    fn execute(&mut self) -> u32 {
         let operation = self.fetch_byte();
+        let mut taken = false;
         match operation {
             0x00 => {} //nop
             0x01 => {
@@ -78,7 +188,7 @@ impl CPU {
                 self.reg.a = self.alu_rrc(self.reg.a);
                 self.reg.set_flag(FZ, false);
             }
-            0x10 => {} //TODO STOP FUNCTION
+            0x10 => return self.stop(),
             0x11 => {
                 let val = self.fetch_word();
                 self.reg.write_16b(DE, val);
@@ -94,7 +204,9 @@ impl CPU {
                 self.reg.a = self.alu_rl(self.reg.a);
                 self.reg.set_flag(FZ, false);
             }
-            0x18 => self.jr(true),
+            0x18 => {
+                self.jr(true);
+            }
             0x19 => self.alu_add_16b(DE),
             0x1A => self.reg.a = self.mem.read_byte(self.reg.read_16b(DE)),
             0x1B => self
@@ -107,7 +219,7 @@ impl CPU {
                 self.reg.a = self.alu_rr(self.reg.a);
                 self.reg.set_flag(FZ, false);
             }
-            0x20 => self.jr(!self.reg.get_flag(FZ)),
+            0x20 => taken = self.jr(!self.reg.get_flag(FZ)),
             0x21 => {
                 let val = self.fetch_word();
                 self.reg.write_16b(HL, val);
@@ -120,7 +232,7 @@ impl CPU {
             0x25 => self.alu_dec(H),
             0x26 => self.reg.h = self.fetch_byte(),
             0x27 => self.alu_daa(),
-            0x28 => self.jr(self.reg.get_flag(FZ)),
+            0x28 => taken = self.jr(self.reg.get_flag(FZ)),
             0x29 => self.alu_add_16b(HL),
             0x2A => self.reg.a = self.mem.read_byte(self.reg.hl_inc()),
             0x2B => self
@@ -134,7 +246,7 @@ impl CPU {
                 self.reg.set_flag(FN, true);
                 self.reg.set_flag(FH, true);
             }
-            0x30 => self.jr(!self.reg.get_flag(FC)),
+            0x30 => taken = self.jr(!self.reg.get_flag(FC)),
             0x31 => self.reg.sp = self.fetch_word(),
             0x32 => self.mem.write_byte(self.reg.hl_dec(), self.reg.a),
             0x33 => self.reg.sp = self.reg.sp.wrapping_add(1),
@@ -147,7 +259,7 @@ impl CPU {
             0x37 => self
                 .reg
                 .set_flags(self.reg.get_flag(FZ), false, false, true),
-            0x38 => self.jr(self.reg.get_flag(FC)),
+            0x38 => taken = self.jr(self.reg.get_flag(FC)),
             0x39 => self.alu_add_16b(SP),
             0x3A => self.reg.a = self.mem.read_byte(self.reg.hl_dec()),
             0x3B => self.reg.sp = self.reg.sp.wrapping_sub(1),
@@ -157,7 +269,7 @@ impl CPU {
             0x3F => self
                 .reg
                 .set_flags(self.reg.get_flag(FZ), false, false, !self.reg.get_flag(FC)),
-            0x40 => self.reg.b = self.reg.b,
+            0x40 => {} //ld b,b
             0x41 => self.reg.b = self.reg.c,
             0x42 => self.reg.b = self.reg.d,
             0x43 => self.reg.b = self.reg.e,
@@ -166,7 +278,7 @@ impl CPU {
             0x46 => self.reg.b = self.mem.read_byte(self.reg.read_16b(HL)),
             0x47 => self.reg.b = self.reg.a,
             0x48 => self.reg.c = self.reg.b,
-            0x49 => self.reg.c = self.reg.c,
+            0x49 => {} //ld c,c
             0x4B => self.reg.c = self.reg.d,
             0x4C => self.reg.c = self.reg.e,
             0x4D => self.reg.c = self.reg.h,
@@ -175,7 +287,7 @@ impl CPU {
             0x4F => self.reg.c = self.reg.a,
             0x50 => self.reg.d = self.reg.b,
             0x51 => self.reg.d = self.reg.c,
-            0x52 => self.reg.d = self.reg.d,
+            0x52 => {} //ld d,d
             0x53 => self.reg.d = self.reg.e,
             0x54 => self.reg.d = self.reg.h,
             0x55 => self.reg.d = self.reg.l,
@@ -184,7 +296,7 @@ impl CPU {
             0x58 => self.reg.e = self.reg.b,
             0x59 => self.reg.e = self.reg.c,
             0x5A => self.reg.e = self.reg.d,
-            0x5B => self.reg.e = self.reg.e,
+            0x5B => {} //ld e,e
             0x5C => self.reg.e = self.reg.h,
             0x5D => self.reg.e = self.reg.l,
             0x5E => self.reg.e = self.mem.read_byte(self.reg.read_16b(HL)),
@@ -193,7 +305,7 @@ impl CPU {
             0x61 => self.reg.h = self.reg.c,
             0x62 => self.reg.h = self.reg.d,
             0x63 => self.reg.h = self.reg.e,
-            0x64 => self.reg.h = self.reg.h,
+            0x64 => {} //ld h,h
             0x65 => self.reg.h = self.reg.l,
             0x66 => self.reg.h = self.mem.read_byte(self.reg.read_16b(HL)),
             0x67 => self.reg.h = self.reg.a,
@@ -202,7 +314,7 @@ impl CPU {
             0x6A => self.reg.l = self.reg.d,
             0x6B => self.reg.l = self.reg.e,
             0x6C => self.reg.l = self.reg.h,
-            0x6D => self.reg.l = self.reg.l,
+            0x6D => {} //ld l,l
             0x6E => self.reg.l = self.mem.read_byte(self.reg.read_16b(HL)),
             0x6F => self.reg.l = self.reg.a,
             0x70 => self.mem.write_byte(self.reg.read_16b(HL), self.reg.b),
@@ -211,7 +323,7 @@ impl CPU {
             0x73 => self.mem.write_byte(self.reg.read_16b(HL), self.reg.e),
             0x74 => self.mem.write_byte(self.reg.read_16b(HL), self.reg.h),
             0x75 => self.mem.write_byte(self.reg.read_16b(HL), self.reg.l),
-            0x76 => self.is_halted = true,
+            0x76 => self.halt(),
             0x77 => self.mem.write_byte(self.reg.read_16b(HL), self.reg.a),
             0x78 => self.reg.a = self.reg.b,
             0x79 => self.reg.a = self.reg.c,
@@ -220,7 +332,7 @@ impl CPU {
             0x7C => self.reg.a = self.reg.h,
             0x7D => self.reg.a = self.reg.l,
             0x7E => self.reg.a = self.mem.read_byte(self.reg.read_16b(HL)),
-            0x7F => self.reg.a = self.reg.a,
+            0x7F => {} //ld a,a
             0x80 => self.alu_add(self.reg.b),
             0x81 => self.alu_add(self.reg.c),
             0x82 => self.alu_add(self.reg.d),
@@ -285,14 +397,16 @@ impl CPU {
             0xBD => self.alu_cp(self.reg.l),
             0xBE => self.alu_cp(self.mem.read_byte(self.reg.read_16b(HL))),
             0xBF => self.alu_cp(self.reg.a),
-            0xC0 => self.ret(!self.reg.get_flag(FZ)),
+            0xC0 => taken = self.ret(!self.reg.get_flag(FZ)),
             0xC1 => {
                 let word = self.pop_stack();
                 self.reg.write_16b(BC, word);
             }
-            0xC2 => self.jp(!self.reg.get_flag(FZ)),
-            0xC3 => self.jp(true),
-            0xC4 => self.call(!self.reg.get_flag(FZ)),
+            0xC2 => taken = self.jp(!self.reg.get_flag(FZ)),
+            0xC3 => {
+                self.jp(true);
+            }
+            0xC4 => taken = self.call(!self.reg.get_flag(FZ)),
             0xC5 => {
                 let word = self.reg.read_16b(BC);
                 self.push_stack(word);
@@ -302,25 +416,29 @@ impl CPU {
                 self.alu_add(val);
             }
             0xC7 => self.rst(0x00),
-            0xC8 => self.ret(self.reg.get_flag(FZ)),
-            0xC9 => self.ret(true),
-            0xCA => self.jp(self.reg.get_flag(FZ)),
-            0xCB => self.execute_cb(),
-            0xCC => self.call(self.reg.get_flag(FZ)),
-            0xCD => self.call(true),
+            0xC8 => taken = self.ret(self.reg.get_flag(FZ)),
+            0xC9 => {
+                self.ret(true);
+            }
+            0xCA => taken = self.jp(self.reg.get_flag(FZ)),
+            0xCB => return self.execute_cb(),
+            0xCC => taken = self.call(self.reg.get_flag(FZ)),
+            0xCD => {
+                self.call(true);
+            }
             0xCE => {
                 let val = self.fetch_byte();
                 self.alu_adc(val);
             }
             0xCF => self.rst(0x08),
-            0xD0 => self.ret(!self.reg.get_flag(FC)),
+            0xD0 => taken = self.ret(!self.reg.get_flag(FC)),
             0xD1 => {
                 let word = self.pop_stack();
                 self.reg.write_16b(DE, word);
             }
-            0xD2 => self.jp(!self.reg.get_flag(FC)),
-            0xD3 => {} //unused
-            0xD4 => self.call(!self.reg.get_flag(FC)),
+            0xD2 => taken = self.jp(!self.reg.get_flag(FC)),
+            0xD3 => self.is_locked = true, //illegal
+            0xD4 => taken = self.call(!self.reg.get_flag(FC)),
             0xD5 => {
                 let word = self.reg.read_16b(DE);
                 self.push_stack(word);
@@ -330,15 +448,15 @@ impl CPU {
                 self.alu_sub(val);
             }
             0xD7 => self.rst(0x10),
-            0xD8 => self.ret(self.reg.get_flag(FC)),
+            0xD8 => taken = self.ret(self.reg.get_flag(FC)),
             0xD9 => {
                 self.ret(true);
                 self.ime = true;
             }
-            0xDA => self.jp(self.reg.get_flag(FC)),
-            0xDB => {} //unused
-            0xDC => self.call(self.reg.get_flag(FC)),
-            0xDD => {} //unused
+            0xDA => taken = self.jp(self.reg.get_flag(FC)),
+            0xDB => self.is_locked = true, //illegal
+            0xDC => taken = self.call(self.reg.get_flag(FC)),
+            0xDD => self.is_locked = true, //illegal
             0xDE => {
                 let val = self.fetch_byte();
                 self.alu_sbc(val);
@@ -356,8 +474,8 @@ impl CPU {
                 let addr = self.reg.c as u16 + 0xFF00;
                 self.mem.write_byte(addr, self.reg.a);
             }
-            0xE3 => {} //unused
-            0xE4 => {} //unused
+            0xE3 => self.is_locked = true, //illegal
+            0xE4 => self.is_locked = true, //illegal
             0xE5 => {
                 let word = self.reg.read_16b(HL);
                 self.push_stack(word);
@@ -373,9 +491,9 @@ impl CPU {
                 let addr = self.fetch_word();
                 self.mem.write_byte(addr, self.reg.a);
             }
-            0xEB => {} //unused
-            0xEC => {} //unused
-            0xED => {} //unused
+            0xEB => self.is_locked = true, //illegal
+            0xEC => self.is_locked = true, //illegal
+            0xED => self.is_locked = true, //illegal
             0xEE => {
                 let val = self.fetch_byte();
                 self.alu_xor(val);
@@ -393,8 +511,11 @@ impl CPU {
                 let addr = self.reg.c as u16 + 0xFF00;
                 self.reg.a = self.mem.read_byte(addr);
             }
-            0xF3 => self.ime = false,
-            0xF4 => {} //unused
+            0xF3 => {
+                self.ime = false;
+                self.ime_pending = false;
+            }
+            0xF4 => self.is_locked = true, //illegal
             0xF5 => {
                 let word = self.reg.read_16b(AF);
                 self.push_stack(word);
@@ -413,18 +534,21 @@ impl CPU {
                 let addr = self.fetch_word();
                 self.reg.a = self.mem.read_byte(addr);
             }
-            0xFB => self.ime = true,
-            0xFC => {} //unused
-            0xFD => {} //unused
+            0xFB => self.ime_pending = true,
+            0xFC => self.is_locked = true, //illegal
+            0xFD => self.is_locked = true, //illegal
             0xFE => {
                 let val = self.fetch_byte();
                 self.alu_cp(val);
             }
             0xFF => self.rst(0x38),
         }
+        let extra = if taken { branch_cycles(operation) } else { 0 };
+        (INSTR_CYCLES[operation as usize] + extra) as u32
     }
 
-    fn execute_cb(&mut self) {
+    //CB_INSTR_CYCLES already includes the fetch of the 0xCB prefix
+    fn execute_cb(&mut self) -> u32 {
         let operation = self.fetch_byte();
         match operation {
             0x00 => self.reg.b = self.alu_rlc(self.reg.b),
@@ -756,27 +880,53 @@ impl CPU {
             }
             0xFF => self.reg.a = self.set_bit(self.reg.a, 7),
         }
+        CB_INSTR_CYCLES[operation as usize] as u32
     }
 
     fn fetch_byte(&mut self) -> u8 {
-        let byte = self.mem.read_byte(self.reg.sp);
-        self.reg.sp = self.reg.sp.wrapping_add(1);
+        let byte = self.mem.read_byte(self.reg.pc);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.reg.pc = self.reg.pc.wrapping_add(1);
+        }
         byte
     }
 
+    //On cgb a prepared speed switch is performed instead of entering low power mode
+    fn stop(&mut self) -> u32 {
+        self.fetch_byte();
+        self.mem.timer.write_byte(0xFF04, 0);
+        if self.mem.switch_speed() {
+            return SPEED_SWITCH_CYCLES;
+        }
+        self.is_stopped = true;
+        self.stop_lines = self.mem.joypad.lines();
+        INSTR_CYCLES[0x10] as u32
+    }
+
+    //With ime unset and an interrupt already pending halt exits immediately and the next byte is read twice
+    fn halt(&mut self) {
+        if !self.ime && self.mem.pending_interrupts() != 0 {
+            self.halt_bug = true;
+        } else {
+            self.is_halted = true;
+        }
+    }
+
     fn fetch_word(&mut self) -> u16 {
-        let word = self.mem.read_word(self.reg.sp);
-        self.reg.sp = self.reg.sp.wrapping_add(2);
+        let word = self.mem.read_word(self.reg.pc);
+        self.reg.pc = self.reg.pc.wrapping_add(2);
         word
     }
 
     fn push_stack(&mut self, value: u16) {
-        self.reg.sp -= 2;
+        self.reg.sp = self.reg.sp.wrapping_sub(2);
         self.mem.write_word(self.reg.sp, value);
     }
     fn pop_stack(&mut self) -> u16 {
         let word = self.mem.read_word(self.reg.sp);
-        self.reg.sp += 2;
+        self.reg.sp = self.reg.sp.wrapping_add(2);
         word
     }
 
@@ -984,7 +1134,7 @@ impl CPU {
 
     fn alu_swap(&mut self, operand: u8) -> u8 {
         self.reg.set_flags(operand == 0, false, false, false);
-        (operand << 4) | (operand >> 4)
+        operand.rotate_right(4)
     }
 
     fn test_bit(&mut self, operand: u8, bit: u8) {
@@ -1000,35 +1150,36 @@ impl CPU {
         operand | (1 << bit)
     }
 
-    fn jr(&mut self, condition: bool) {
+    fn jr(&mut self, condition: bool) -> bool {
+        let offset = self.fetch_byte() as i8;
         if condition {
-            self.reg.pc = self.reg.pc.wrapping_add(self.fetch_byte() as u16);
-        } else {
-            self.reg.pc = self.reg.pc.wrapping_add(1);
+            self.reg.pc = self.reg.pc.wrapping_add(offset as u16);
         }
+        condition
     }
 
-    fn ret(&mut self, condition: bool) {
+    fn ret(&mut self, condition: bool) -> bool {
         if condition {
             self.reg.pc = self.pop_stack();
         }
+        condition
     }
 
-    fn jp(&mut self, condition: bool) {
+    fn jp(&mut self, condition: bool) -> bool {
+        let addr = self.fetch_word();
         if condition {
-            self.reg.pc = self.fetch_word();
-        } else {
-            self.reg.pc = self.reg.pc.wrapping_add(2);
+            self.reg.pc = addr;
         }
+        condition
     }
 
-    fn call(&mut self, condition: bool) {
+    fn call(&mut self, condition: bool) -> bool {
+        let addr = self.fetch_word();
         if condition {
             self.push_stack(self.reg.pc);
-            self.reg.pc = self.fetch_word();
-        } else {
-            self.reg.pc = self.reg.pc.wrapping_add(2);
+            self.reg.pc = addr;
         }
+        condition
     }
 
     fn rst(&mut self, pointer: u8) {
@@ -1036,3 +1187,28 @@ impl CPU {
         self.reg.pc = pointer as u16;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::header_checksum;
+
+    #[test]
+    fn starts_at_entry_point_after_boot() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100] = 0x3C; //INC A
+        rom[0x143] = 0x80; //cgb support
+        rom[0x14D] = header_checksum(&rom);
+        let mut cpu = CPU::new();
+        cpu.mem.set_model(Model::Cgb);
+        cpu.load_cartridge(Cartridge::from_bytes(rom).unwrap());
+
+        assert_eq!(cpu.reg.pc, 0x0100);
+        assert_eq!(cpu.reg.sp, 0xFFFE);
+        assert_eq!(cpu.reg.a, 0x11);
+        assert_eq!(cpu.mem.read_byte(0xFF40), 0x91);
+        cpu.step();
+        assert_eq!(cpu.reg.pc, 0x0101);
+        assert_eq!(cpu.reg.a, 0x12);
+    }
+}
@@ -0,0 +1,36 @@
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    VBlank = 0x01,
+    Stat = 0x02,
+    Timer = 0x04,
+    Serial = 0x08,
+    Joypad = 0x10,
+}
+
+//Highest priority first
+pub const INTERRUPTS: [Interrupt; 5] = [
+    Interrupt::VBlank,
+    Interrupt::Stat,
+    Interrupt::Timer,
+    Interrupt::Serial,
+    Interrupt::Joypad,
+];
+
+impl Interrupt {
+    pub fn vector(self) -> u16 {
+        match self {
+            Interrupt::VBlank => 0x40,
+            Interrupt::Stat => 0x48,
+            Interrupt::Timer => 0x50,
+            Interrupt::Serial => 0x58,
+            Interrupt::Joypad => 0x60,
+        }
+    }
+
+    pub fn highest_priority(flags: u8) -> Option<Interrupt> {
+        INTERRUPTS
+            .iter()
+            .copied()
+            .find(|&int| flags & int as u8 != 0)
+    }
+}
@@ -0,0 +1,144 @@
+//Buttons as their bit in a ButtonMask, directions in the low and actions in the high nibble
+#[allow(dead_code)] //most variants are only named by frontends and tests
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Button {
+    Right = 0x01,
+    Left = 0x02,
+    Up = 0x04,
+    Down = 0x08,
+    A = 0x10,
+    B = 0x20,
+    Select = 0x40,
+    Start = 0x80,
+}
+
+//Set of held buttons, a set bit means pressed
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ButtonMask(pub u8);
+
+impl ButtonMask {
+    pub fn with(self, button: Button) -> ButtonMask {
+        ButtonMask(self.0 | button as u8)
+    }
+}
+
+pub struct Joypad {
+    pressed: ButtonMask,
+    select: u8, //P1 bits 4-5, a cleared bit selects the directions or the actions
+    pub interrupt: bool,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            pressed: ButtonMask::default(),
+            select: 0x30,
+            interrupt: false,
+        }
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.set_state(self.pressed.with(button));
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.set_state(ButtonMask(self.pressed.0 & !(button as u8)));
+    }
+
+    pub fn set_state(&mut self, pressed: ButtonMask) {
+        let old = self.lines();
+        self.pressed = pressed;
+        self.update_interrupt(old);
+    }
+
+    //Input lines 0-3 that are pulled low by a pressed button in a selected group
+    pub fn lines(&self) -> u8 {
+        let mut lines = 0;
+        if self.select & (0x1 << 4) == 0 {
+            lines |= self.pressed.0 & 0x0F;
+        }
+        if self.select & (0x1 << 5) == 0 {
+            lines |= self.pressed.0 >> 4;
+        }
+        lines
+    }
+
+    //The interrupt fires when any input line goes from high to low
+    fn update_interrupt(&mut self, old: u8) {
+        if !old & self.lines() != 0 {
+            self.interrupt = true;
+        }
+    }
+
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            //buttons are active low, the upper 2 bits are unused and read as 1
+            0xFF00 => 0xC0 | self.select | (!self.lines() & 0x0F),
+            _ => panic!("Not a valid joypad memory area"),
+        }
+    }
+
+    pub fn write_byte(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF00 => {
+                //selecting a group with a held button pulls its line low as well
+                let old = self.lines();
+                self.select = value & 0x30;
+                self.update_interrupt(old);
+            }
+            _ => panic!("Not a valid joypad memory area"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_bits_pick_the_group() {
+        let mut joypad = Joypad::new();
+        joypad.set_state(ButtonMask::default().with(Button::Down).with(Button::A));
+        //nothing selected reads all released
+        assert_eq!(joypad.read_byte(0xFF00), 0xFF);
+        joypad.write_byte(0xFF00, 0x20);
+        assert_eq!(joypad.read_byte(0xFF00), 0xE7);
+        joypad.write_byte(0xFF00, 0x10);
+        assert_eq!(joypad.read_byte(0xFF00), 0xDE);
+        joypad.write_byte(0xFF00, 0x00);
+        assert_eq!(joypad.read_byte(0xFF00), 0xC6);
+    }
+
+    #[test]
+    fn upper_bits_read_as_one() {
+        let mut joypad = Joypad::new();
+        for value in [0x00, 0x0F, 0x3F, 0xC0, 0xFF] {
+            joypad.write_byte(0xFF00, value);
+            assert_eq!(joypad.read_byte(0xFF00) & 0xC0, 0xC0);
+            assert_eq!(joypad.read_byte(0xFF00) & 0x30, value & 0x30);
+        }
+    }
+
+    #[test]
+    fn interrupt_on_high_to_low_of_selected_lines() {
+        let mut joypad = Joypad::new();
+        joypad.write_byte(0xFF00, 0x20);
+        //buttons of the unselected group do not pull a line low
+        joypad.press(Button::Start);
+        assert!(!joypad.interrupt);
+        joypad.press(Button::Left);
+        assert!(joypad.interrupt);
+        joypad.interrupt = false;
+        //releasing is a low to high transition
+        joypad.release(Button::Left);
+        assert!(!joypad.interrupt);
+        //selecting the actions pulls line 3 low through start
+        joypad.write_byte(0xFF00, 0x10);
+        assert!(joypad.interrupt);
+        joypad.interrupt = false;
+        //a line that is already low does not fire again
+        joypad.write_byte(0xFF00, 0x00);
+        joypad.press(Button::Down);
+        assert!(!joypad.interrupt);
+    }
+}
@@ -1,8 +1,229 @@
+mod cartridge;
+mod compat;
 mod cpu;
-mod register;
+mod interrupt;
+mod joypad;
+mod mbc;
 mod memory;
 mod ppu;
+mod register;
+mod resample;
+mod rtc;
 mod sound;
 mod timer;
+mod wav;
+
+use std::env;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use cartridge::Cartridge;
+use compat::PaletteOverride;
+use cpu::CPU;
+use memory::Model;
+use ppu::{DmgPalette, Renderer};
+use wav::Recorder;
+
+//M-cycles between save file flushes, one second of emulated time
+const SAVE_INTERVAL: u32 = 1 << 20;
+//M-cycles of about one frame, recorded audio and quit requests are handled in between
+const FRAME_CYCLES: u32 = 17556;
+
+//Set by SIGINT and SIGTERM, the main loop then writes the save and exits
+static QUIT: AtomicBool = AtomicBool::new(false);
+
+fn main() {
+    let mut path = None;
+    let mut fifo = false;
+    let mut palette = DmgPalette::Grey;
+    let mut model = None;
+    let mut palette_override = None;
+    let mut record_path = None;
+    let mut record_frames = 600;
+    let mut record_channels = false;
+    let mut sample_rate = 48000;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            //the pixel fifo renderer is slower but handles mid-scanline register changes
+            "--fifo" => fifo = true,
+            "--palette" => match args.next().map(|scheme| scheme.parse()) {
+                Some(Ok(scheme)) => palette = scheme,
+                Some(Err(err)) => usage(&err),
+                None => usage("--palette needs a value"),
+            },
+            "--model" => match args.next().as_deref() {
+                Some("dmg") => model = Some(Model::Dmg),
+                Some("cgb") => model = Some(Model::Cgb),
+                _ => usage("--model needs dmg or cgb"),
+            },
+            //direction and button held during the cgb boot logo, picks the palette of a dmg game
+            "--compat-palette" => match args
+                .next()
+                .map(|buttons| buttons.parse::<PaletteOverride>())
+            {
+                Some(Ok(buttons)) => palette_override = Some(buttons),
+                Some(Err(err)) => usage(&err),
+                None => usage("--compat-palette needs a value"),
+            },
+            //writes the audio of the first frames to a wav file and exits
+            "--record" => match args.next() {
+                Some(wav_path) => record_path = Some(wav_path),
+                None => usage("--record needs a file"),
+            },
+            "--record-frames" => match args.next().map(|frames| frames.parse()) {
+                Some(Ok(frames)) => record_frames = frames,
+                _ => usage("--record-frames needs a number of frames"),
+            },
+            //also record every channel to its own file, e.g. song_ch1.wav next to song.wav
+            "--record-channels" => record_channels = true,
+            "--sample-rate" => match args.next().map(|rate| rate.parse()) {
+                Some(Ok(rate)) if rate > 0 => sample_rate = rate,
+                _ => usage("--sample-rate needs a rate in Hz"),
+            },
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => usage(&format!("unexpected argument {}", arg)),
+        }
+    }
+    let path = match path {
+        Some(path) => path,
+        None => usage("no rom given"),
+    };
+
+    let cartridge = match Cartridge::from_file(Path::new(&path)) {
+        Ok(cartridge) => cartridge,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
+        }
+    };
+    let header = &cartridge.header;
+    println!(
+        "{} ({:?}, type {:#04X}, {} KiB rom, {} KiB ram)",
+        header.title,
+        header.cgb_flag,
+        header.cartridge_type.code,
+        header.rom_size / 1024,
+        header.ram_size / 1024
+    );
+
+    for warning in cartridge.warnings() {
+        eprintln!("{}: warning: {}", path, warning);
+    }
+
+    if let Some(save_path) = cartridge.save_path() {
+        println!("saving to {}", save_path.display());
+    }
+
+    let mut cpu = CPU::new();
+    cpu.mem.ppu.set_dmg_palette(palette);
+    if let Some(model) = model {
+        cpu.mem.set_model(model);
+    }
+    if let Some(palette_override) = palette_override {
+        cpu.mem.set_palette_override(palette_override);
+    }
+    cpu.load_cartridge(cartridge);
+    if fifo {
+        cpu.mem.ppu.set_renderer(Renderer::Fifo);
+    }
+    let mut recorder = match record_path {
+        Some(record_path) => match Recorder::start(
+            &mut cpu.mem.apu,
+            Path::new(&record_path),
+            sample_rate,
+            record_frames,
+            record_channels,
+        ) {
+            Ok(recorder) => Some(recorder),
+            Err(err) => {
+                eprintln!("{}: {}", record_path, err);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    install_quit_handler();
+    loop {
+        let mut cycles = 0;
+        while cycles < SAVE_INTERVAL {
+            let mut record_cycles = 0;
+            while record_cycles < FRAME_CYCLES {
+                record_cycles += cpu.step();
+            }
+            cycles += record_cycles;
+            if let Some(rec) = &mut recorder {
+                if record(rec, &mut cpu) {
+                    if let Some(rec) = recorder.take() {
+                        finish_recording(rec);
+                    }
+                    flush_save(&mut cpu);
+                    process::exit(0);
+                }
+            }
+            if QUIT.load(Ordering::Relaxed) {
+                flush_save(&mut cpu);
+                process::exit(0);
+            }
+        }
+        flush_save(&mut cpu);
+    }
+}
+
+//process::exit skips destructors, so quitting has to go through the main loop to save
+#[cfg(unix)]
+fn install_quit_handler() {
+    const SIGINT: i32 = 2;
+    const SIGTERM: i32 = 15;
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    }
+    extern "C" fn on_quit(_signum: i32) {
+        QUIT.store(true, Ordering::Relaxed);
+    }
+    //the handler only stores to an atomic, which is safe in signal context
+    unsafe {
+        signal(SIGINT, on_quit);
+        signal(SIGTERM, on_quit);
+    }
+}
+
+#[cfg(not(unix))]
+fn install_quit_handler() {}
+
+//Returns true once all requested frames are recorded
+fn record(recorder: &mut Recorder, cpu: &mut CPU) -> bool {
+    match recorder.record(&mut cpu.mem.apu) {
+        Ok(done) => done,
+        Err(err) => {
+            eprintln!("could not write recording: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+fn finish_recording(recorder: Recorder) {
+    if let Err(err) = recorder.finish() {
+        eprintln!("could not write recording: {}", err);
+        process::exit(1);
+    }
+}
+
+fn usage(err: &str) -> ! {
+    eprintln!("{}", err);
+    eprintln!(
+        "usage: corroded_boy [--fifo] [--palette green|grey|pocket|RRGGBB,RRGGBB,RRGGBB,RRGGBB] \
+         [--model dmg|cgb] [--compat-palette up|down|left|right[+a|+b]] [--record <out.wav>] \
+         [--record-frames <n>] [--record-channels] [--sample-rate <hz>] <rom.gb|rom.gbc>"
+    );
+    process::exit(2);
+}
 
-fn main() {}
+fn flush_save(cpu: &mut CPU) {
+    if let Some(cartridge) = cpu.mem.cartridge_mut() {
+        if let Err(err) = cartridge.flush_save() {
+            eprintln!("could not write save file: {}", err);
+        }
+    }
+}
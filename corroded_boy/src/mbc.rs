@@ -0,0 +1,508 @@
+use crate::rtc::{Clock, Rtc};
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+//Memory bank controllers only hold their registers, the rom and ram themselves are owned by the cartridge
+#[allow(clippy::upper_case_acronyms)]
+pub trait MBC {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8;
+    fn write_rom(&mut self, addr: u16, value: u8);
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8;
+    //returns true if the ram or clock contents changed, so the save only gets written when needed
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8) -> bool;
+
+    //extra state that is persisted after the ram, e.g. the mbc3 clock
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_state(&mut self, _data: &[u8]) {}
+
+    #[allow(dead_code)] //only read through Cartridge::rumble
+    fn rumble(&self) -> bool {
+        false
+    }
+}
+
+fn rom_byte(rom: &[u8], bank: usize, addr: u16) -> u8 {
+    let banks = (rom.len() / ROM_BANK_SIZE).max(1);
+    let offset = (bank % banks) * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1));
+    *rom.get(offset).unwrap_or(&0xFF)
+}
+
+fn store(ram: &mut [u8], offset: usize, value: u8) -> bool {
+    let changed = ram[offset] != value;
+    ram[offset] = value;
+    changed
+}
+
+fn ram_offset(ram: &[u8], bank: usize, addr: u16) -> Option<usize> {
+    if ram.is_empty() {
+        return None;
+    }
+    Some((bank * RAM_BANK_SIZE + (addr as usize - 0xA000)) % ram.len())
+}
+
+pub struct NoMBC;
+
+impl MBC for NoMBC {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        *rom.get(addr as usize).unwrap_or(&0xFF)
+    }
+
+    fn write_rom(&mut self, _addr: u16, _value: u8) {}
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        match ram_offset(ram, 0, addr) {
+            Some(offset) => ram[offset],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8) -> bool {
+        match ram_offset(ram, 0, addr) {
+            Some(offset) => store(ram, offset, value),
+            None => false,
+        }
+    }
+}
+
+pub struct MBC1 {
+    ram_en: bool,
+    bank1: u8, //5 bit rom bank register
+    bank2: u8, //2 bit upper rom / ram bank register
+    mode: bool,
+    multicart: bool,
+}
+
+impl MBC1 {
+    pub fn new(rom: &[u8]) -> MBC1 {
+        MBC1 {
+            ram_en: false,
+            bank1: 1,
+            bank2: 0,
+            mode: false,
+            multicart: MBC1::is_multicart(rom),
+        }
+    }
+
+    //MBC1M carts are 1 MiB and carry a second boot logo at the start of game 1 (bank 0x10)
+    fn is_multicart(rom: &[u8]) -> bool {
+        let logo = 0x0104..0x0134;
+        let second_logo = 0x10 * ROM_BANK_SIZE + 0x0104..0x10 * ROM_BANK_SIZE + 0x0134;
+        rom.len() == 0x100000 && rom[logo] == rom[second_logo]
+    }
+
+    //in multicart wiring bit 4 of bank1 is not connected and bank2 sits one bit lower
+    fn upper_bits(&self) -> usize {
+        if self.multicart {
+            (self.bank2 as usize) << 4
+        } else {
+            (self.bank2 as usize) << 5
+        }
+    }
+
+    fn lower_bits(&self) -> usize {
+        if self.multicart {
+            self.bank1 as usize & 0x0F
+        } else {
+            self.bank1 as usize
+        }
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.mode {
+            self.bank2 as usize
+        } else {
+            0
+        }
+    }
+}
+
+impl MBC for MBC1 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => {
+                let bank = if self.mode { self.upper_bits() } else { 0 };
+                rom_byte(rom, bank, addr)
+            }
+            _ => rom_byte(rom, self.upper_bits() | self.lower_bits(), addr),
+        }
+    }
+
+    fn write_rom(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_en = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                //the zero check looks at all 5 bits, so bank 0x20 maps to 0x21 and so on
+                self.bank1 = match value & 0x1F {
+                    0 => 1,
+                    bank => bank,
+                }
+            }
+            0x4000..=0x5FFF => self.bank2 = value & 0x03,
+            _ => self.mode = value & 0x01 != 0,
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        if !self.ram_en {
+            return 0xFF;
+        }
+        match ram_offset(ram, self.ram_bank(), addr) {
+            Some(offset) => ram[offset],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8) -> bool {
+        if !self.ram_en {
+            return false;
+        }
+        match ram_offset(ram, self.ram_bank(), addr) {
+            Some(offset) => store(ram, offset, value),
+            None => false,
+        }
+    }
+}
+
+pub struct MBC3 {
+    ram_en: bool,
+    rom_bank: u8,
+    ram_bank: u8, //0x00-0x07 select a ram bank, 0x08-0x0C an rtc register
+    rtc: Option<Rtc>,
+}
+
+impl MBC3 {
+    pub fn new(clock: Option<Box<dyn Clock>>) -> MBC3 {
+        MBC3 {
+            ram_en: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rtc: clock.map(Rtc::new),
+        }
+    }
+}
+
+impl MBC for MBC3 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => rom_byte(rom, 0, addr),
+            _ => rom_byte(rom, self.rom_bank as usize, addr),
+        }
+    }
+
+    fn write_rom(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_en = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                self.rom_bank = match value & 0x7F {
+                    0 => 1,
+                    bank => bank,
+                }
+            }
+            0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+            _ => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(value);
+                }
+            }
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        if !self.ram_en {
+            return 0xFF;
+        }
+        match (self.ram_bank, &self.rtc) {
+            (0x00..=0x07, _) => match ram_offset(ram, self.ram_bank as usize, addr) {
+                Some(offset) => ram[offset],
+                None => 0xFF,
+            },
+            (0x08..=0x0C, Some(rtc)) => rtc.read_byte(self.ram_bank),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8) -> bool {
+        if !self.ram_en {
+            return false;
+        }
+        match (self.ram_bank, &mut self.rtc) {
+            (0x00..=0x07, _) => match ram_offset(ram, self.ram_bank as usize, addr) {
+                Some(offset) => store(ram, offset, value),
+                None => false,
+            },
+            (0x08..=0x0C, Some(rtc)) => rtc.write_byte(self.ram_bank, value),
+            _ => false,
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        match &self.rtc {
+            Some(rtc) => rtc.save().to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    fn load_state(&mut self, data: &[u8]) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load(data);
+        }
+    }
+}
+
+pub const MBC2_RAM_SIZE: usize = 0x200;
+
+pub struct MBC2 {
+    ram_en: bool,
+    rom_bank: u8,
+}
+
+impl MBC2 {
+    pub fn new() -> MBC2 {
+        MBC2 {
+            ram_en: false,
+            rom_bank: 1,
+        }
+    }
+}
+
+impl MBC for MBC2 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => rom_byte(rom, 0, addr),
+            _ => rom_byte(rom, self.rom_bank as usize, addr),
+        }
+    }
+
+    //both registers live in 0x0000-0x3FFF, address bit 8 selects between them
+    fn write_rom(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x3FFF if addr & 0x0100 == 0 => self.ram_en = value & 0x0F == 0x0A,
+            0x0000..=0x3FFF => {
+                self.rom_bank = match value & 0x0F {
+                    0 => 1,
+                    bank => bank,
+                }
+            }
+            _ => {}
+        }
+    }
+
+    //512 half bytes mirrored across the whole ram area, the upper nibble is open bus
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        if !self.ram_en {
+            return 0xFF;
+        }
+        0xF0 | ram[(addr as usize - 0xA000) % MBC2_RAM_SIZE]
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8) -> bool {
+        self.ram_en && store(ram, (addr as usize - 0xA000) % MBC2_RAM_SIZE, value & 0x0F)
+    }
+}
+
+pub struct MBC5 {
+    ram_en: bool,
+    rom_bank: u16, //9 bit, bank 0 can be mapped to 0x4000-0x7FFF
+    ram_bank: u8,
+    has_rumble: bool,
+    rumble: bool,
+}
+
+impl MBC5 {
+    pub fn new(has_rumble: bool) -> MBC5 {
+        MBC5 {
+            ram_en: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble,
+            rumble: false,
+        }
+    }
+}
+
+impl MBC for MBC5 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => rom_byte(rom, 0, addr),
+            _ => rom_byte(rom, self.rom_bank as usize, addr),
+        }
+    }
+
+    fn write_rom(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_en = value == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+            0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | (value as u16 & 0x01) << 8,
+            0x4000..=0x5FFF => {
+                //on rumble carts bit 3 drives the motor instead of the ram bank
+                if self.has_rumble {
+                    self.rumble = value & 0x08 != 0;
+                    self.ram_bank = value & 0x07;
+                } else {
+                    self.ram_bank = value & 0x0F;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        if !self.ram_en {
+            return 0xFF;
+        }
+        match ram_offset(ram, self.ram_bank as usize, addr) {
+            Some(offset) => ram[offset],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, value: u8) -> bool {
+        if !self.ram_en {
+            return false;
+        }
+        match ram_offset(ram, self.ram_bank as usize, addr) {
+            Some(offset) => store(ram, offset, value),
+            None => false,
+        }
+    }
+
+    fn rumble(&self) -> bool {
+        self.rumble
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Every bank starts with its 9 bit number, low byte first
+    fn banked_rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+            rom[bank * ROM_BANK_SIZE + 1] = (bank >> 8) as u8;
+        }
+        rom
+    }
+
+    fn bank_at(mbc: &dyn MBC, rom: &[u8], addr: u16) -> usize {
+        mbc.read_rom(rom, addr) as usize | (mbc.read_rom(rom, addr + 1) as usize) << 8
+    }
+
+    #[test]
+    fn mbc1_bank_zero_check_uses_five_bits() {
+        let mut rom = banked_rom(128);
+        rom[0x0104] = 0xCE; //no second logo, not a multicart
+        let mut mbc = MBC1::new(&rom);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0x01);
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0x21);
+        mbc.write_rom(0x2000, 0x05);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0x25);
+        //only the 5 low bits are checked, 0x20 is still zero
+        mbc.write_rom(0x2000, 0x20);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0x21);
+    }
+
+    #[test]
+    fn mbc1_mode_1_maps_bank2() {
+        let mut rom = banked_rom(128);
+        rom[0x0104] = 0xCE;
+        let mut mbc = MBC1::new(&rom);
+        let mut ram = vec![0; 4 * RAM_BANK_SIZE];
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x02);
+        //mode 0 keeps bank 0 and ram bank 0 in place
+        assert_eq!(bank_at(&mbc, &rom, 0x0000), 0x00);
+        assert!(mbc.write_ram(&mut ram, 0xA000, 0x11));
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(bank_at(&mbc, &rom, 0x0000), 0x40);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0x41);
+        assert!(mbc.write_ram(&mut ram, 0xA000, 0x22));
+        assert_eq!(ram[0], 0x11);
+        assert_eq!(ram[2 * RAM_BANK_SIZE], 0x22);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 0x22);
+    }
+
+    #[test]
+    fn mbc1_multicart_wiring() {
+        let mut rom = banked_rom(64);
+        for i in 0x0104..0x0134 {
+            rom[i] = i as u8;
+            rom[0x10 * ROM_BANK_SIZE + i] = i as u8;
+        }
+        let mut mbc = MBC1::new(&rom);
+        //bank2 sits at bit 4 and bit 4 of bank1 is ignored
+        mbc.write_rom(0x4000, 0x01);
+        mbc.write_rom(0x2000, 0x12);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0x12);
+        mbc.write_rom(0x4000, 0x02);
+        mbc.write_rom(0x2000, 0x13);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0x23);
+        //0x10 passes the zero check but maps the first bank of the game
+        mbc.write_rom(0x2000, 0x10);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0x20);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(bank_at(&mbc, &rom, 0x0000), 0x20);
+    }
+
+    #[test]
+    fn mbc5_nine_bit_bank() {
+        let rom = banked_rom(512);
+        let mut mbc = MBC5::new(false);
+        mbc.write_rom(0x2000, 0x34);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0x034);
+        mbc.write_rom(0x3000, 0x01);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0x134);
+        //the low byte keeps the high bit and the other way round
+        mbc.write_rom(0x2000, 0xFF);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0x1FF);
+        mbc.write_rom(0x3000, 0x00);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0x0FF);
+        //unlike the older controllers bank 0 can be mapped to 0x4000-0x7FFF
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0x000);
+        assert_eq!(bank_at(&mbc, &rom, 0x0000), 0x000);
+    }
+
+    #[test]
+    fn mbc2_register_select() {
+        let rom = banked_rom(16);
+        let mut mbc = MBC2::new();
+        let mut ram = vec![0; MBC2_RAM_SIZE];
+        //a8 set selects the rom bank, clear the ram enable
+        mbc.write_rom(0x0100, 0x0A);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0x0A);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 0xFF);
+        mbc.write_rom(0x0000, 0x0A);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 0xF0);
+        //the whole range decodes the same way, bank 0 maps to 1
+        mbc.write_rom(0x3F00, 0x00);
+        assert_eq!(bank_at(&mbc, &rom, 0x4000), 0x01);
+        mbc.write_rom(0x3E00, 0x00);
+        assert!(!mbc.write_ram(&mut ram, 0xA000, 0x05));
+    }
+
+    #[test]
+    fn mbc2_nibble_ram_mirrors() {
+        let mut mbc = MBC2::new();
+        let mut ram = vec![0; MBC2_RAM_SIZE];
+        mbc.write_rom(0x0000, 0x0A);
+        assert!(mbc.write_ram(&mut ram, 0xA001, 0xAB));
+        assert_eq!(ram[1], 0x0B);
+        //only the low nibble is stored, the upper one reads as set
+        assert_eq!(mbc.read_ram(&ram, 0xA001), 0xFB);
+        //512 bytes repeat through 0xA000-0xBFFF
+        assert_eq!(mbc.read_ram(&ram, 0xA201), 0xFB);
+        assert_eq!(mbc.read_ram(&ram, 0xBE01), 0xFB);
+        assert!(mbc.write_ram(&mut ram, 0xBFFF, 0x07));
+        assert_eq!(mbc.read_ram(&ram, 0xA1FF), 0xF7);
+        //writing the same nibble again is no change
+        assert!(!mbc.write_ram(&mut ram, 0xA001, 0x1B));
+    }
+}
@@ -1,18 +1,437 @@
-pub struct Memory {}
+use crate::cartridge::{Cartridge, CgbFlag};
+use crate::compat::{CompatPalettes, PaletteOverride};
+use crate::interrupt::Interrupt;
+use crate::joypad::{Button, ButtonMask, Joypad};
+use crate::ppu::ppu;
+use crate::sound::Apu;
+use crate::timer::Timer;
+
+const OAM_SIZE: usize = 0xA0;
+
+//Io registers as the boot rom leaves them, the same on both models
+const POST_BOOT_IO: [(u16, u8); 6] = [
+    (0xFF0F, 0xE1), //IF, vblank is already requested
+    (0xFF24, 0x77), //NR50
+    (0xFF25, 0xF3), //NR51
+    (0xFF40, 0x91), //LCDC, lcd and background on
+    (0xFF41, 0x85), //STAT, no interrupt sources selected
+    (0xFF47, 0xFC), //BGP
+];
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Model {
+    Dmg,
+    Cgb,
+}
+
+pub struct Memory {
+    cartridge: Option<Cartridge>,
+    wram: [u8; 0x2000],
+    hram: [u8; 0x7F],
+    io: [u8; 0x80],
+    int_flag: u8,
+    int_en: u8,
+    model: Option<Model>, //picked from the cartridge header if not set before loading
+    palette_override: Option<PaletteOverride>,
+    cgb_mode: bool,
+    double_speed: bool,
+    speed_switch_armed: bool, //KEY1 bit 0
+    dma_source: u8,           //FF46, upper byte of the oam dma source address
+    dma_index: usize,         //next byte of the running oam dma, OAM_SIZE when idle
+    dma_delay: u8,            //M-cycles until a requested oam dma starts
+    dma_byte: u8,             //last byte moved by the oam dma, seen by the cpu on bus conflicts
+    hdma_source: u16,
+    hdma_dest: u16,    //offset into vram
+    hdma_blocks: u8,   //remaining 16 byte blocks minus one, HDMA5 bits 0-6
+    hdma_hblank: bool, //an hblank dma is running
+    stall: u32,        //M-cycles the cpu has to wait for a general purpose or hblank dma
+    pub ppu: ppu,
+    pub apu: Apu,
+    pub timer: Timer,
+    pub joypad: Joypad,
+}
 
 impl Memory {
     pub fn new() -> Memory {
-        Memory {}
+        Memory {
+            cartridge: None,
+            wram: [0; 0x2000],
+            hram: [0; 0x7F],
+            io: [0xFF; 0x80],
+            int_flag: 0,
+            int_en: 0,
+            model: None,
+            palette_override: None,
+            cgb_mode: false,
+            double_speed: false,
+            speed_switch_armed: false,
+            dma_source: 0xFF,
+            dma_index: OAM_SIZE,
+            dma_delay: 0,
+            dma_byte: 0xFF,
+            hdma_source: 0,
+            hdma_dest: 0,
+            hdma_blocks: 0x7F,
+            hdma_hblank: false,
+            stall: 0,
+            ppu: ppu::new(),
+            apu: Apu::new(),
+            timer: Timer::new(),
+            joypad: Joypad::new(),
+        }
+    }
+
+    //Model and palette override have to be set before the cartridge is loaded
+    pub fn set_model(&mut self, model: Model) {
+        self.model = Some(model);
+    }
+
+    pub fn set_palette_override(&mut self, palette_override: PaletteOverride) {
+        self.palette_override = Some(palette_override);
+    }
+
+    pub fn load_cartridge(&mut self, cartridge: Cartridge) {
+        let dmg_only = cartridge.header.cgb_flag == CgbFlag::DmgOnly;
+        let model = match self.model {
+            Some(model) => model,
+            None if dmg_only => Model::Dmg,
+            None => Model::Cgb,
+        };
+        self.cgb_mode = model == Model::Cgb && !dmg_only;
+        self.ppu.set_cgb_mode(self.cgb_mode);
+        self.apu.set_model(model);
+        //a cgb running a dmg game uses the palettes its boot rom picked for the title
+        if model == Model::Cgb && dmg_only {
+            let palettes = match self.palette_override {
+                Some(palette_override) => CompatPalettes::for_override(palette_override),
+                None => {
+                    CompatPalettes::for_title(&cartridge.header.licensee, cartridge.title_bytes())
+                }
+            };
+            self.ppu.set_compat_palettes(palettes);
+        }
+        self.cartridge = Some(cartridge);
+        self.model = Some(model);
+        //there is no boot rom, start with the state it hands over to the cartridge
+        for (addr, value) in POST_BOOT_IO {
+            self.write_byte(addr, value);
+        }
+    }
+
+    pub fn model(&self) -> Option<Model> {
+        self.model
+    }
+
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    //Called by STOP, returns true if a prepared speed switch was performed
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.cgb_mode || !self.speed_switch_armed {
+            return false;
+        }
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+        true
+    }
+
+    //T-cycles of the normal speed clock that pass during the given number of cpu M-cycles,
+    //ppu and apu always run at normal speed while the cpu and timer are sped up
+    pub fn ticks(&self, cycles: u32) -> u32 {
+        if self.double_speed {
+            cycles * 2
+        } else {
+            cycles * 4
+        }
+    }
+
+    pub fn cartridge_mut(&mut self) -> Option<&mut Cartridge> {
+        self.cartridge.as_mut()
+    }
+
+    pub fn request_interrupt(&mut self, int: Interrupt) {
+        self.int_flag |= int as u8;
+    }
+
+    pub fn clear_interrupt(&mut self, int: Interrupt) {
+        self.int_flag &= !(int as u8);
+    }
+
+    //Input from the frontend, the joypad interrupt is requested right away instead of on the next step
+    #[allow(dead_code)] //the cli has no input device to drive it, only tests do
+    pub fn press(&mut self, button: Button) {
+        self.joypad.press(button);
+        self.joypad_interrupt();
+    }
+
+    #[allow(dead_code)] //see press
+    pub fn release(&mut self, button: Button) {
+        self.joypad.release(button);
+        self.joypad_interrupt();
+    }
+
+    #[allow(dead_code)] //see press
+    pub fn set_state(&mut self, pressed: ButtonMask) {
+        self.joypad.set_state(pressed);
+        self.joypad_interrupt();
+    }
+
+    fn joypad_interrupt(&mut self) {
+        if self.joypad.interrupt {
+            self.joypad.interrupt = false;
+            self.request_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    //Interrupts that are both requested and enabled, regardless of ime
+    pub fn pending_interrupts(&self) -> u8 {
+        self.int_flag & self.int_en & 0x1F
+    }
+
+    pub fn dma_active(&self) -> bool {
+        self.dma_index < OAM_SIZE
+    }
+
+    //Oam dma copies one byte per M-cycle after a one cycle startup delay,
+    //writing FF46 while a transfer is running restarts it
+    fn step_dma(&mut self) {
+        if self.dma_active() {
+            //sources above 0xDFFF read the echo of wram
+            let mut addr = (self.dma_source as u16) << 8 | self.dma_index as u16;
+            if addr >= 0xE000 {
+                addr -= 0x2000;
+            }
+            self.dma_byte = self.bus_read(addr);
+            self.ppu.write_oam(self.dma_index, self.dma_byte);
+            self.dma_index += 1;
+        }
+        if self.dma_delay > 0 {
+            self.dma_delay -= 1;
+            if self.dma_delay == 0 {
+                self.dma_index = 0;
+            }
+        }
+    }
+
+    //The dma owns oam and the bus it reads from, vram or the external bus with rom and wram.
+    //Hram and the io registers sit on the cpu's internal bus and stay accessible
+    fn dma_conflict(&self, addr: u16) -> bool {
+        if !self.dma_active() {
+            return false;
+        }
+        let vram_source = (0x80..=0x9F).contains(&self.dma_source);
+        match addr {
+            0x8000..=0x9FFF => vram_source,
+            0x0000..=0x7FFF | 0xA000..=0xFDFF => !vram_source,
+            0xFE00..=0xFEFF => true,
+            _ => false,
+        }
+    }
+
+    //Copies one 16 byte block from the hdma source into the selected vram bank and returns true
+    //once the transfer is done, the cpu is halted for 8 M-cycles at normal speed and 16 at double speed
+    fn hdma_block(&mut self) -> bool {
+        for _ in 0..16 {
+            let value = self.bus_read(self.hdma_source);
+            self.ppu.write_vram(0x8000 | self.hdma_dest, value);
+            self.hdma_source = self.hdma_source.wrapping_add(1);
+            self.hdma_dest = (self.hdma_dest + 1) & 0x1FFF;
+        }
+        self.stall += if self.double_speed { 16 } else { 8 };
+
+        //the transfer also ends when the destination wraps past the end of vram
+        let (blocks, done) = self.hdma_blocks.overflowing_sub(1);
+        self.hdma_blocks = blocks & 0x7F;
+        if done || self.hdma_dest == 0 {
+            self.hdma_blocks = 0x7F;
+            return true;
+        }
+        false
+    }
+
+    fn write_hdma5(&mut self, value: u8) {
+        if self.hdma_hblank && value & 0x80 == 0 {
+            //clearing bit 7 cancels a running hblank dma, the remaining length stays readable
+            self.hdma_hblank = false;
+            return;
+        }
+        self.hdma_blocks = value & 0x7F;
+        if value & 0x80 == 0 {
+            while !self.hdma_block() {}
+            return;
+        }
+        self.hdma_hblank = true;
+        //with the lcd off no hblank is coming, one block is copied right away
+        if !self.ppu.lcd_enabled() && self.hdma_block() {
+            self.hdma_hblank = false;
+        }
+    }
+
+    //M-cycles the cpu has to sit out for dma transfers since the last call
+    pub fn take_stall(&mut self) -> u32 {
+        std::mem::take(&mut self.stall)
+    }
+
+    //Advances all components by the given number of cpu M-cycles
+    pub fn step(&mut self, cycles: u32) {
+        //the apu watches the timer's counter for its frame sequencer, so both advance together
+        let ticks = self.ticks(1);
+        for _ in 0..cycles {
+            self.step_dma();
+            self.timer.step(1);
+            self.apu
+                .step(ticks, self.timer.counter(), self.double_speed);
+        }
+
+        if self.timer.interrupt {
+            self.timer.interrupt = false;
+            self.request_interrupt(Interrupt::Timer);
+        }
+        //catches input that went to the joypad directly instead of through press and release
+        self.joypad_interrupt();
+
+        self.ppu.step(self.ticks(cycles));
+        //long steps like a gdma stall or a speed switch can span several hblanks
+        for _ in 0..std::mem::take(&mut self.ppu.hblanks_started) {
+            if self.hdma_hblank && self.hdma_block() {
+                self.hdma_hblank = false;
+            }
+        }
+        if self.ppu.vblank_interrupt {
+            self.ppu.vblank_interrupt = false;
+            self.request_interrupt(Interrupt::VBlank);
+        }
+        if self.ppu.stat_interrupt {
+            self.ppu.stat_interrupt = false;
+            self.request_interrupt(Interrupt::Stat);
+        }
     }
 
     pub fn read_byte(&self, addr: u16) -> u8 {
-        0
+        match addr {
+            0xFE00..=0xFEFF if self.dma_conflict(addr) => 0xFF,
+            _ if self.dma_conflict(addr) => self.dma_byte,
+            _ => self.bus_read(addr),
+        }
+    }
+
+    fn bus_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x7FFF | 0xA000..=0xBFFF => match &self.cartridge {
+                Some(cartridge) => cartridge.read_byte(addr),
+                None => 0xFF,
+            },
+            0x8000..=0x9FFF => self.ppu.read_byte(addr),
+            0xC000..=0xDFFF => self.wram[addr as usize - 0xC000],
+            0xE000..=0xFDFF => self.wram[addr as usize - 0xE000], //echo ram
+            0xFE00..=0xFE9F => self.ppu.read_byte(addr),
+            0xFEA0..=0xFEFF => 0xFF, //unusable
+            0xFF00 => self.joypad.read_byte(addr),
+            0xFF04..=0xFF07 => self.timer.read_byte(addr),
+            0xFF0F => 0xE0 | self.int_flag, //upper 3 bits are unused and read as 1
+            0xFF10..=0xFF3F => self.apu.read_byte(addr),
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B => self.ppu.read_byte(addr),
+            0xFF46 => self.dma_source,
+            0xFF4F | 0xFF68..=0xFF6B => self.ppu.read_byte(addr),
+            0xFF4D if self.cgb_mode => {
+                let mut byte = 0x7E;
+                byte |= if self.double_speed { 0x1 << 7 } else { 0 };
+                byte |= if self.speed_switch_armed { 0x1 } else { 0 };
+                byte
+            }
+            0xFF4D => 0xFF,
+            0xFF51..=0xFF54 if self.cgb_mode => 0xFF, //write only
+            0xFF55 if self.cgb_mode => {
+                let mut byte = self.hdma_blocks;
+                byte |= if self.hdma_hblank { 0 } else { 0x1 << 7 };
+                byte
+            }
+            0xFF01..=0xFF7F => self.io[addr as usize - 0xFF00],
+            0xFF80..=0xFFFE => self.hram[addr as usize - 0xFF80],
+            0xFFFF => self.int_en,
+        }
+    }
+
+    pub fn write_byte(&mut self, addr: u16, value: u8) {
+        if self.dma_conflict(addr) {
+            return;
+        }
+        match addr {
+            0x0000..=0x7FFF | 0xA000..=0xBFFF => {
+                if let Some(cartridge) = &mut self.cartridge {
+                    cartridge.write_byte(addr, value);
+                }
+            }
+            0x8000..=0x9FFF => self.ppu.write_byte(addr, value),
+            0xC000..=0xDFFF => self.wram[addr as usize - 0xC000] = value,
+            0xE000..=0xFDFF => self.wram[addr as usize - 0xE000] = value,
+            0xFE00..=0xFE9F => self.ppu.write_byte(addr, value),
+            0xFEA0..=0xFEFF => {}
+            0xFF00 => {
+                self.joypad.write_byte(addr, value);
+                self.joypad_interrupt();
+            }
+            0xFF04..=0xFF07 => self.timer.write_byte(addr, value),
+            0xFF0F => self.int_flag = value & 0x1F,
+            0xFF10..=0xFF3F => self.apu.write_byte(addr, value),
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B => self.ppu.write_byte(addr, value),
+            0xFF46 => {
+                self.dma_source = value;
+                self.dma_delay = 1;
+            }
+            0xFF4F | 0xFF68..=0xFF6B => self.ppu.write_byte(addr, value),
+            0xFF51 if self.cgb_mode => {
+                self.hdma_source = (self.hdma_source & 0x00FF) | (value as u16) << 8
+            }
+            0xFF52 if self.cgb_mode => {
+                self.hdma_source = (self.hdma_source & 0xFF00) | (value & 0xF0) as u16
+            }
+            0xFF53 if self.cgb_mode => {
+                self.hdma_dest = (self.hdma_dest & 0x00FF) | (value as u16 & 0x1F) << 8
+            }
+            0xFF54 if self.cgb_mode => {
+                self.hdma_dest = (self.hdma_dest & 0x1F00) | (value & 0xF0) as u16
+            }
+            0xFF55 if self.cgb_mode => self.write_hdma5(value),
+            0xFF4D => self.speed_switch_armed = self.cgb_mode && value & 0x01 != 0,
+            0xFF01..=0xFF7F => self.io[addr as usize - 0xFF00] = value,
+            0xFF80..=0xFFFE => self.hram[addr as usize - 0xFF80] = value,
+            0xFFFF => self.int_en = value,
+        }
     }
 
-    pub fn write_byte(&self, addr: u16, value: u8) {}
     pub fn read_word(&self, addr: u16) -> u16 {
-        0
+        (self.read_byte(addr) as u16) | (self.read_byte(addr.wrapping_add(1)) as u16) << 8
+    }
+
+    pub fn write_word(&mut self, addr: u16, value: u16) {
+        self.write_byte(addr, (value & 0x00FF) as u8);
+        self.write_byte(addr.wrapping_add(1), (value >> 8) as u8);
     }
+}
 
-    pub fn write_word(&self, addr: u16, value: u16) {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joypad_interrupt_reaches_if() {
+        let mut mem = Memory::new();
+        mem.write_byte(0xFF00, 0x10);
+        mem.press(Button::A);
+        assert_eq!(
+            mem.read_byte(0xFF0F) & Interrupt::Joypad as u8,
+            Interrupt::Joypad as u8
+        );
+
+        //input that bypasses the wrappers is picked up on the next step
+        mem.write_byte(0xFF0F, 0x00);
+        mem.joypad.press(Button::B);
+        mem.step(1);
+        assert_eq!(
+            mem.read_byte(0xFF0F) & Interrupt::Joypad as u8,
+            Interrupt::Joypad as u8
+        );
+    }
 }
@@ -1,3 +1,177 @@
+const OAM_SEARCH_DOTS: u32 = 80;
+const PIXEL_TRANSFER_DOTS: u32 = 172;
+const LINE_DOTS: u32 = 456;
+const VBLANK_START: u8 = 144;
+const LAST_LINE: u8 = 153;
+
+use crate::compat::CompatPalettes;
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+const MAX_LINE_OBJECTS: usize = 10;
+//dots before the first bg fetch of a line starts, covers the initial fetch the hardware throws away
+const FIFO_START_DELAY: u32 = 5;
+const FETCH_DOTS: u8 = 6;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamSearch = 2,
+    PixelTransfer = 3,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Renderer {
+    Scanline, //draws the whole line at the end of pixel transfer, fast
+    Fifo,     //models the pixel fifos and fetcher dot by dot, needed for mid-line effects
+}
+
+//Maps the four dmg shades, lightest first, to 0xRRGGBB
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DmgPalette {
+    Green,
+    Grey,
+    Pocket,
+    Custom([u32; 4]),
+}
+
+impl DmgPalette {
+    pub fn shades(&self) -> [u32; 4] {
+        match self {
+            DmgPalette::Green => [0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F],
+            DmgPalette::Grey => [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000],
+            DmgPalette::Pocket => [0xC4CFA1, 0x8B956D, 0x4D533C, 0x1F1F1F],
+            DmgPalette::Custom(shades) => *shades,
+        }
+    }
+}
+
+//Accepts a scheme name or four comma separated RRGGBB hex colors
+impl FromStr for DmgPalette {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DmgPalette, String> {
+        match s {
+            "green" => return Ok(DmgPalette::Green),
+            "grey" | "gray" => return Ok(DmgPalette::Grey),
+            "pocket" => return Ok(DmgPalette::Pocket),
+            _ => {}
+        }
+        let invalid = || {
+            format!(
+                "expected green, grey, pocket or four RRGGBB colors, got {}",
+                s
+            )
+        };
+        let colors = s
+            .split(',')
+            .map(|color| u32::from_str_radix(color.trim().trim_start_matches('#'), 16))
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| invalid())?;
+        match colors[..] {
+            [c0, c1, c2, c3] if colors.iter().all(|&c| c <= 0xFFFFFF) => {
+                Ok(DmgPalette::Custom([c0, c1, c2, c3]))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Object {
+    y: u8, //screen position + 16
+    x: u8, //screen position + 8
+    tile: u8,
+    attrs: u8,
+    index: u8,
+}
+
+impl Object {
+    fn behind_bg(&self) -> bool {
+        self.attrs & (0x1 << 7) != 0
+    }
+
+    fn y_flip(&self) -> bool {
+        self.attrs & (0x1 << 6) != 0
+    }
+
+    fn x_flip(&self) -> bool {
+        self.attrs & (0x1 << 5) != 0
+    }
+
+    fn dmg_palette(&self) -> usize {
+        (self.attrs >> 4) as usize & 0x1
+    }
+
+    fn vram_bank(&self) -> usize {
+        (self.attrs >> 3) as usize & 0x1
+    }
+
+    fn cgb_palette(&self) -> usize {
+        self.attrs as usize & 0x07
+    }
+}
+
+//Bg/window pixel, palette and priority come from the cgb attribute map and are 0 on dmg
+#[derive(Copy, Clone, Default)]
+struct BgPixel {
+    color: u8,
+    palette: u8,
+    priority: bool,
+}
+
+#[derive(Copy, Clone, Default)]
+struct ObjPixel {
+    color: u8,
+    palette: u8,
+    behind_bg: bool,
+    index: u8,
+}
+
+//State of the fifo renderer for the line currently in pixel transfer
+struct PixelFifo {
+    bg: VecDeque<BgPixel>,
+    obj: VecDeque<ObjPixel>,
+    fetch_dots: u8, //progress of the current bg tile fetch
+    fetcher_x: u8,  //tile column of the next bg fetch
+    tile: u8,
+    attrs: u8,
+    tile_lo: u8,
+    tile_hi: u8,
+    window: bool,
+    window_drawn: bool,
+    objects: Vec<Object>, //objects on this line that have not been fetched yet
+    lx: usize,            //next pixel to be drawn
+    discard: u8,          //pixels dropped at the start of the line for fine scx
+    stall: u32,           //dots the pixel output is paused for
+}
+
+impl PixelFifo {
+    fn new() -> PixelFifo {
+        PixelFifo {
+            bg: VecDeque::with_capacity(16),
+            obj: VecDeque::with_capacity(8),
+            fetch_dots: 0,
+            fetcher_x: 0,
+            tile: 0,
+            attrs: 0,
+            tile_lo: 0,
+            tile_hi: 0,
+            window: false,
+            window_drawn: false,
+            objects: Vec::with_capacity(MAX_LINE_OBJECTS),
+            lx: 0,
+            discard: 0,
+            stall: 0,
+        }
+    }
+}
+
+#[allow(non_camel_case_types)]
 pub struct ppu {
     vram: [u8; 0x4000],
     oam: [u8; 0xA0],
@@ -10,6 +184,37 @@ pub struct ppu {
     obj_en: bool,
     bg_win_en: bool,
     cur_vram_bank: u8,
+    mode: Mode,
+    line_dots: u32, //dots since the start of the current line
+    ly: u8,
+    lyc: u8,
+    lyc_int: bool,
+    mode0_int: bool,
+    mode1_int: bool,
+    mode2_int: bool,
+    stat_line: bool, //or of all enabled stat sources, the interrupt fires on its rising edge
+    scy: u8,
+    scx: u8,
+    wy: u8,
+    wx: u8,
+    bgp: u8,
+    obp: [u8; 2],
+    bg_shades: [u32; 4],       //0xRRGGBB for the four dmg shades
+    obj_shades: [[u32; 4]; 2], //differ from the bg shades only when colourised by the cgb boot rom
+    cgb_mode: bool,
+    window_line: u8, //internal line counter, only advances on lines the window was drawn on
+    bg_line: [BgPixel; SCREEN_WIDTH], //background pixels of the current line
+    bg_palettes: [u8; 0x40], //cgb palette ram, 8 palettes of 4 little endian rgb555 colors
+    obj_palettes: [u8; 0x40],
+    bcps: u8, //index into bg palette ram, bit 7 enables auto increment on writes
+    ocps: u8,
+    renderer: Renderer,
+    fifo: PixelFifo,
+    frame: Vec<u32>,
+    pub frame_ready: bool,
+    pub hblanks_started: u32, //hblanks entered since the last check, drive the cgb hblank dma
+    pub vblank_interrupt: bool,
+    pub stat_interrupt: bool,
 }
 
 impl ppu {
@@ -26,14 +231,533 @@ impl ppu {
             obj_en: false,
             bg_win_en: false,
             cur_vram_bank: 0,
+            mode: Mode::HBlank,
+            line_dots: 0,
+            ly: 0,
+            lyc: 0,
+            lyc_int: false,
+            mode0_int: false,
+            mode1_int: false,
+            mode2_int: false,
+            stat_line: false,
+            scy: 0,
+            scx: 0,
+            wy: 0,
+            wx: 0,
+            bgp: 0xFC,
+            obp: [0xFF; 2],
+            bg_shades: DmgPalette::Grey.shades(),
+            obj_shades: [DmgPalette::Grey.shades(); 2],
+            cgb_mode: false,
+            window_line: 0,
+            bg_line: [BgPixel::default(); SCREEN_WIDTH],
+            bg_palettes: [0xFF; 0x40],
+            obj_palettes: [0xFF; 0x40],
+            bcps: 0,
+            ocps: 0,
+            renderer: Renderer::Scanline,
+            fifo: PixelFifo::new(),
+            frame: vec![DmgPalette::Grey.shades()[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame_ready: false,
+            hblanks_started: 0,
+            vblank_interrupt: false,
+            stat_interrupt: false,
         }
     }
 
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcd_en
+    }
+
+    //In cgb mode objects are prioritised purely by oam index instead of by x coordinate
+    pub fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+    }
+
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.bg_shades = palette.shades();
+        self.obj_shades = [palette.shades(); 2];
+    }
+
+    //Colours a dmg game the way the cgb boot rom does in compatibility mode
+    pub fn set_compat_palettes(&mut self, palettes: CompatPalettes) {
+        self.bg_shades = palettes.bg;
+        self.obj_shades = palettes.obj;
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+    }
+
+    //Oam dma writes regardless of the current mode
+    pub fn write_oam(&mut self, index: usize, value: u8) {
+        self.oam[index] = value;
+    }
+
+    //Hdma writes into the selected bank regardless of the current mode
+    pub fn write_vram(&mut self, addr: u16, value: u8) {
+        self.vram[(self.cur_vram_bank as usize * 0x2000) | (addr as usize & 0x1FFF)] = value;
+    }
+
+    //Last completed frame as 0xRRGGBB pixels, row by row
+    #[allow(dead_code)] //the cli has no display yet to draw it on
+    pub fn frame(&self) -> &[u32] {
+        &self.frame
+    }
+
+    //Advances the ppu by the given number of dots (T-cycles at normal speed)
+    pub fn step(&mut self, dots: u32) {
+        if !self.lcd_en {
+            return;
+        }
+        for _ in 0..dots {
+            self.tick();
+        }
+    }
+
+    fn tick(&mut self) {
+        self.line_dots += 1;
+        match self.mode {
+            Mode::OamSearch if self.line_dots == OAM_SEARCH_DOTS => {
+                self.mode = Mode::PixelTransfer;
+                if self.renderer == Renderer::Fifo {
+                    self.start_fifo_line();
+                }
+            }
+            Mode::PixelTransfer => {
+                let done = match self.renderer {
+                    Renderer::Scanline => self.line_dots == OAM_SEARCH_DOTS + PIXEL_TRANSFER_DOTS,
+                    Renderer::Fifo => self.fifo_tick(),
+                };
+                if !done {
+                    return;
+                }
+                match self.renderer {
+                    Renderer::Scanline => self.render_line(),
+                    Renderer::Fifo if self.fifo.window_drawn => self.window_line += 1,
+                    Renderer::Fifo => {}
+                }
+                self.mode = Mode::HBlank;
+                self.hblanks_started += 1;
+            }
+            Mode::HBlank if self.line_dots == LINE_DOTS => {
+                self.line_dots = 0;
+                self.ly += 1;
+                if self.ly == VBLANK_START {
+                    self.mode = Mode::VBlank;
+                    self.vblank_interrupt = true;
+                    self.frame_ready = true;
+                    self.window_line = 0;
+                } else {
+                    self.mode = Mode::OamSearch;
+                }
+            }
+            Mode::VBlank if self.line_dots == LINE_DOTS => {
+                self.line_dots = 0;
+                if self.ly == LAST_LINE {
+                    self.ly = 0;
+                    self.mode = Mode::OamSearch;
+                } else {
+                    self.ly += 1;
+                }
+            }
+            _ => return,
+        }
+        self.update_stat();
+    }
+
+    //Offset into vram of the given row of a bg/window tile, LCDC bit 4 selects between
+    //unsigned indexing from 0x8000 and signed indexing from 0x9000
+    fn tile_row_addr(&self, tile: u8, row: u8) -> usize {
+        let base = if self.bg_win_tile_area {
+            tile as usize * 16
+        } else {
+            (0x1000 + tile as i8 as isize * 16) as usize
+        };
+        base + row as usize * 2
+    }
+
+    fn tile_color(lo: u8, hi: u8, bit: u8) -> u8 {
+        ((hi >> bit) & 0x1) << 1 | ((lo >> bit) & 0x1)
+    }
+
+    //Attributes of a bg/window tile, stored in vram bank 1 at the same position as the tile index.
+    //Bits 0-2 palette, bit 3 tile data bank, bit 5 x flip, bit 6 y flip, bit 7 priority over objects
+    fn tile_attrs(&self, offset: usize) -> u8 {
+        if self.cgb_mode {
+            self.vram[0x2000 + offset]
+        } else {
+            0
+        }
+    }
+
+    //Low and high byte of the given tile row, honouring the bank and y flip attributes
+    fn tile_row(&self, tile: u8, attrs: u8, row: u8) -> (u8, u8) {
+        let row = if attrs & (0x1 << 6) != 0 {
+            7 - row
+        } else {
+            row
+        };
+        let addr = ((attrs as usize >> 3) & 0x1) * 0x2000 + self.tile_row_addr(tile, row);
+        (self.vram[addr], self.vram[addr + 1])
+    }
+
+    fn bg_pixel(attrs: u8, lo: u8, hi: u8, col: u8) -> BgPixel {
+        let bit = if attrs & (0x1 << 5) != 0 {
+            col
+        } else {
+            7 - col
+        };
+        BgPixel {
+            color: ppu::tile_color(lo, hi, bit),
+            palette: attrs & 0x07,
+            priority: attrs & (0x1 << 7) != 0,
+        }
+    }
+
+    //Pixel at x/y in the 256x256 pixel tile map starting at map
+    fn map_pixel(&self, map: usize, x: u8, y: u8) -> BgPixel {
+        let offset = map + (y as usize / 8) * 32 + x as usize / 8;
+        let attrs = self.tile_attrs(offset);
+        let (lo, hi) = self.tile_row(self.vram[offset], attrs, y % 8);
+        ppu::bg_pixel(attrs, lo, hi, x % 8)
+    }
+
+    //On dmg LCDC bit 0 blanks bg and window, on cgb they stay visible and only lose priority
+    fn bg_visible(&self) -> bool {
+        self.bg_win_en || self.cgb_mode
+    }
+
+    fn render_line(&mut self) {
+        let bg_map = if self.bg_tile_area { 0x1C00 } else { 0x1800 };
+        let win_map = if self.win_tile_area { 0x1C00 } else { 0x1800 };
+        let window_visible = self.win_en && self.ly >= self.wy && self.wx <= 166;
+        let mut window_drawn = false;
+
+        for x in 0..SCREEN_WIDTH {
+            self.bg_line[x] = if !self.bg_visible() {
+                BgPixel::default()
+            } else if window_visible && x + 7 >= self.wx as usize {
+                window_drawn = true;
+                self.map_pixel(win_map, (x + 7 - self.wx as usize) as u8, self.window_line)
+            } else {
+                let bg_x = (x as u8).wrapping_add(self.scx);
+                self.map_pixel(bg_map, bg_x, self.ly.wrapping_add(self.scy))
+            };
+        }
+
+        if window_drawn {
+            self.window_line += 1;
+        }
+
+        let objects = if self.obj_en {
+            self.render_objects()
+        } else {
+            [ObjPixel::default(); SCREEN_WIDTH]
+        };
+        for (x, obj) in objects.iter().enumerate() {
+            self.frame[self.ly as usize * SCREEN_WIDTH + x] = self.mix(self.bg_line[x], *obj);
+        }
+    }
+
+    fn obj_height(&self) -> u8 {
+        if self.obj_size {
+            16
+        } else {
+            8
+        }
+    }
+
+    //The first 10 objects in oam order that overlap the current line, x does not matter
+    fn line_objects(&self) -> Vec<Object> {
+        let height = self.obj_height();
+        self.oam
+            .chunks(4)
+            .enumerate()
+            .map(|(index, entry)| Object {
+                y: entry[0],
+                x: entry[1],
+                tile: entry[2],
+                attrs: entry[3],
+                index: index as u8,
+            })
+            .filter(|obj| {
+                let top = obj.y as i16 - 16;
+                (top..top + height as i16).contains(&(self.ly as i16))
+            })
+            .take(MAX_LINE_OBJECTS)
+            .collect()
+    }
+
+    //Pixel of an object at column col (0-7) of the current line
+    fn obj_pixel(&self, obj: &Object, col: u8) -> ObjPixel {
+        let height = self.obj_height();
+        //the fifo selects objects at the start of the line, if the size shrinks before one is
+        //fetched only the row bits of the new size are used
+        let mut row = (self.ly + 16 - obj.y) & (height - 1);
+        if obj.y_flip() {
+            row = height - 1 - row;
+        }
+        let tile = if height == 16 {
+            obj.tile & 0xFE
+        } else {
+            obj.tile
+        };
+        let (bank, palette) = if self.cgb_mode {
+            (obj.vram_bank(), obj.cgb_palette())
+        } else {
+            (0, obj.dmg_palette())
+        };
+        let addr = bank * 0x2000 + tile as usize * 16 + row as usize * 2;
+        let bit = if obj.x_flip() { col } else { 7 - col };
+        ObjPixel {
+            color: ppu::tile_color(self.vram[addr], self.vram[addr + 1], bit),
+            palette: palette as u8,
+            behind_bg: obj.behind_bg(),
+            index: obj.index,
+        }
+    }
+
+    //The highest priority opaque object pixel at every x of the current line
+    fn render_objects(&self) -> [ObjPixel; SCREEN_WIDTH] {
+        let mut objects = self.line_objects();
+        //on dmg the object with the smaller x wins, ties go to the lower oam index (stable sort)
+        if !self.cgb_mode {
+            objects.sort_by_key(|obj| obj.x);
+        }
+
+        let mut line = [ObjPixel::default(); SCREEN_WIDTH];
+        for (x, pixel) in line.iter_mut().enumerate() {
+            for obj in &objects {
+                let col = x as i16 + 8 - obj.x as i16;
+                if !(0..8).contains(&col) {
+                    continue;
+                }
+                let obj_pixel = self.obj_pixel(obj, col as u8);
+                //the highest priority opaque object decides, even if it ends up behind the background
+                if obj_pixel.color != 0 {
+                    *pixel = obj_pixel;
+                    break;
+                }
+            }
+        }
+        line
+    }
+
+    //Color of the final pixel. Objects lose against non zero bg colors if they are flagged as behind
+    //the bg, on cgb also if the bg tile has its priority bit set, unless LCDC bit 0 is cleared
+    fn mix(&self, bg: BgPixel, obj: ObjPixel) -> u32 {
+        let bg_wins = if self.cgb_mode {
+            self.bg_win_en && bg.color != 0 && (obj.behind_bg || bg.priority)
+        } else {
+            bg.color != 0 && obj.behind_bg
+        };
+        if obj.color != 0 && !bg_wins {
+            self.obj_shade(obj)
+        } else {
+            self.bg_shade(bg)
+        }
+    }
+
+    fn bg_shade(&self, pixel: BgPixel) -> u32 {
+        if self.cgb_mode {
+            return ppu::cgb_color(&self.bg_palettes, pixel.palette, pixel.color);
+        }
+        let shade = (self.bgp >> (pixel.color * 2)) & 0x03;
+        self.bg_shades[shade as usize]
+    }
+
+    fn obj_shade(&self, pixel: ObjPixel) -> u32 {
+        if self.cgb_mode {
+            return ppu::cgb_color(&self.obj_palettes, pixel.palette, pixel.color);
+        }
+        let shade = (self.obp[pixel.palette as usize] >> (pixel.color * 2)) & 0x03;
+        self.obj_shades[pixel.palette as usize][shade as usize]
+    }
+
+    //Converts a little endian rgb555 palette ram entry to 0xRRGGBB
+    fn cgb_color(palettes: &[u8; 0x40], palette: u8, color: u8) -> u32 {
+        let offset = palette as usize * 8 + color as usize * 2;
+        ppu::rgb555(palettes[offset] as u16 | (palettes[offset + 1] as u16) << 8)
+    }
+
+    //Scales the 5 bit channels of a 0bbbbbgggggrrrrr color to 0xRRGGBB
+    pub fn rgb555(color: u16) -> u32 {
+        let channel = |shift: u16| {
+            let c = (color >> shift) as u32 & 0x1F;
+            c << 3 | c >> 2
+        };
+        channel(0) << 16 | channel(5) << 8 | channel(10)
+    }
+
+    fn start_fifo_line(&mut self) {
+        let mut objects = self.line_objects();
+        if !self.obj_en {
+            objects.clear();
+        }
+        //objects are fetched in x order, so overlapping ones resolve like in the scanline renderer
+        objects.sort_by_key(|obj| obj.x);
+        self.fifo.bg.clear();
+        self.fifo.obj.clear();
+        self.fifo.fetch_dots = 0;
+        self.fifo.fetcher_x = 0;
+        self.fifo.window = false;
+        self.fifo.window_drawn = false;
+        self.fifo.objects = objects;
+        self.fifo.lx = 0;
+        self.fifo.discard = self.scx % 8;
+        self.fifo.stall = FIFO_START_DELAY;
+    }
+
+    //One dot of pixel transfer, returns true once the last pixel of the line was drawn
+    fn fifo_tick(&mut self) -> bool {
+        if self.fifo.stall > 0 {
+            self.fifo.stall -= 1;
+            return false;
+        }
+
+        //the window restarts the bg fetcher once the output reaches wx - 7
+        if !self.fifo.window
+            && self.win_en
+            && self.bg_visible()
+            && self.ly >= self.wy
+            && self.fifo.lx + 7 >= self.wx as usize
+            && self.wx <= 166
+        {
+            self.fifo.window = true;
+            self.fifo.window_drawn = true;
+            self.fifo.bg.clear();
+            self.fifo.fetch_dots = 0;
+            self.fifo.fetcher_x = 0;
+            self.fifo.discard = 0;
+        }
+
+        self.fetcher_tick();
+
+        //an object starting at the current pixel pauses output while its row is fetched,
+        //the bg fetcher gets to finish its current tile first
+        if self.fifo.discard == 0 && !self.fifo.bg.is_empty() {
+            let lx = self.fifo.lx;
+            let hit = self
+                .fifo
+                .objects
+                .iter()
+                .position(|obj| (obj.x as usize).saturating_sub(8) <= lx);
+            if let Some(pos) = hit {
+                let obj = self.fifo.objects.remove(pos);
+                self.fetch_object(&obj);
+                self.fifo.stall = 11 - self.fifo.fetch_dots.min(5) as u32;
+                return false;
+            }
+        }
+
+        let bg = match self.fifo.bg.pop_front() {
+            Some(bg) => bg,
+            None => return false,
+        };
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return false;
+        }
+        let bg = if self.bg_visible() {
+            bg
+        } else {
+            BgPixel::default()
+        };
+        let obj = self.fifo.obj.pop_front().unwrap_or_default();
+        let x = self.fifo.lx;
+        self.bg_line[x] = bg;
+        self.frame[self.ly as usize * SCREEN_WIDTH + x] = self.mix(bg, obj);
+        self.fifo.lx += 1;
+        self.fifo.lx == SCREEN_WIDTH
+    }
+
+    //The bg fetcher reads the tile number, low and high data byte 2 dots apart
+    //and then waits until the fifo is empty to push the 8 pixels
+    fn fetcher_tick(&mut self) {
+        self.fifo.fetch_dots = self.fifo.fetch_dots.saturating_add(1);
+        let (map, x, y) = if self.fifo.window {
+            let map = if self.win_tile_area { 0x1C00 } else { 0x1800 };
+            (map, self.fifo.fetcher_x, self.window_line)
+        } else {
+            let map = if self.bg_tile_area { 0x1C00 } else { 0x1800 };
+            let x = (self.scx / 8).wrapping_add(self.fifo.fetcher_x) & 0x1F;
+            (map, x, self.ly.wrapping_add(self.scy))
+        };
+        match self.fifo.fetch_dots {
+            1 => {
+                let offset = map + (y as usize / 8) * 32 + x as usize;
+                self.fifo.tile = self.vram[offset];
+                self.fifo.attrs = self.tile_attrs(offset);
+            }
+            3 => self.fifo.tile_lo = self.tile_row(self.fifo.tile, self.fifo.attrs, y % 8).0,
+            5 => self.fifo.tile_hi = self.tile_row(self.fifo.tile, self.fifo.attrs, y % 8).1,
+            dots if dots >= FETCH_DOTS && self.fifo.bg.is_empty() => {
+                for col in 0..8 {
+                    let pixel =
+                        ppu::bg_pixel(self.fifo.attrs, self.fifo.tile_lo, self.fifo.tile_hi, col);
+                    self.fifo.bg.push_back(pixel);
+                }
+                self.fifo.fetcher_x = self.fifo.fetcher_x.wrapping_add(1);
+                self.fifo.fetch_dots = 0;
+            }
+            _ => {}
+        }
+    }
+
+    //Mixes the row of an object into the object fifo, pixels already in the fifo
+    //keep priority unless a lower oam index wins in cgb mode
+    fn fetch_object(&mut self, obj: &Object) {
+        while self.fifo.obj.len() < 8 {
+            self.fifo.obj.push_back(ObjPixel::default());
+        }
+        //objects partially left of the screen lose their leftmost columns
+        let skip = 8usize.saturating_sub(obj.x as usize);
+        for col in skip..8 {
+            let pixel = self.obj_pixel(obj, col as u8);
+            let slot = &mut self.fifo.obj[col - skip];
+            if pixel.color != 0 && (slot.color == 0 || (self.cgb_mode && pixel.index < slot.index))
+            {
+                *slot = pixel;
+            }
+        }
+    }
+
+    //The index advances after data writes if bit 7 is set, even when the write itself is blocked
+    fn increment_palette_index(spec: u8) -> u8 {
+        if spec & 0x80 == 0 {
+            return spec;
+        }
+        0x80 | ((spec + 1) & 0x3F)
+    }
+
+    fn update_stat(&mut self) {
+        let line = (self.lyc_int && self.ly == self.lyc)
+            || (self.mode0_int && self.mode == Mode::HBlank)
+            || (self.mode1_int && self.mode == Mode::VBlank)
+            || (self.mode2_int && self.mode == Mode::OamSearch);
+        if line && !self.stat_line {
+            self.stat_interrupt = true;
+        }
+        self.stat_line = line;
+    }
+
+    //The cpu can not access vram during pixel transfer and oam during oam search and pixel transfer
+    fn vram_accessible(&self) -> bool {
+        self.mode != Mode::PixelTransfer
+    }
+
+    fn oam_accessible(&self) -> bool {
+        self.mode != Mode::PixelTransfer && self.mode != Mode::OamSearch
+    }
+
     pub fn read_byte(&self, addr: u16) -> u8 {
         match addr {
+            0x8000..=0x9FFF if !self.vram_accessible() => 0xFF,
             0x8000..=0x9FFF => {
                 self.vram[(self.cur_vram_bank as usize * 0x2000) | (addr as usize - 0x8000)]
             }
+            0xFE00..=0xFE9F if !self.oam_accessible() => 0xFF,
             0xFE00..=0xFE9F => self.oam[addr as usize - 0xFE00],
             0xFF40 => {
                 let mut byte = 0x00;
@@ -47,19 +771,57 @@ impl ppu {
                 byte |= if self.bg_win_en { 0x1 } else { 0 };
                 byte
             }
+            0xFF41 => {
+                let mut byte = 0x80;
+                byte |= if self.lyc_int { 0x1 << 6 } else { 0 };
+                byte |= if self.mode2_int { 0x1 << 5 } else { 0 };
+                byte |= if self.mode1_int { 0x1 << 4 } else { 0 };
+                byte |= if self.mode0_int { 0x1 << 3 } else { 0 };
+                byte |= if self.ly == self.lyc { 0x1 << 2 } else { 0 };
+                byte | self.mode as u8
+            }
+            0xFF42 => self.scy,
+            0xFF43 => self.scx,
+            0xFF44 => self.ly,
+            0xFF45 => self.lyc,
+            0xFF47 => self.bgp,
+            0xFF48 => self.obp[0],
+            0xFF49 => self.obp[1],
+            0xFF4A => self.wy,
+            0xFF4B => self.wx,
+            0xFF4F if self.cgb_mode => 0xFE | self.cur_vram_bank,
+            0xFF4F => 0xFF,
+            0xFF68..=0xFF6B if !self.cgb_mode => 0xFF,
+            0xFF68 => 0x40 | self.bcps,
+            0xFF69 if self.mode == Mode::PixelTransfer => 0xFF, //palette ram is locked like vram
+            0xFF69 => self.bg_palettes[self.bcps as usize & 0x3F],
+            0xFF6A => 0x40 | self.ocps,
+            0xFF6B if self.mode == Mode::PixelTransfer => 0xFF,
+            0xFF6B => self.obj_palettes[self.ocps as usize & 0x3F],
             _ => panic!("Not a valid ppu memory area"),
         }
     }
 
     pub fn write_byte(&mut self, addr: u16, value: u8) {
         match addr {
+            0x8000..=0x9FFF if !self.vram_accessible() => {}
             0x8000..=0x9FFF => {
                 self.vram[(self.cur_vram_bank as usize * 0x2000) | (addr as usize - 0x8000)] =
                     value;
             }
+            0xFE00..=0xFE9F if !self.oam_accessible() => {}
             0xFE00..=0xFE9F => self.oam[addr as usize - 0xFE00] = value,
             0xFF40 => {
-                self.lcd_en = value & (0x1 << 7) != 0;
+                let lcd_en = value & (0x1 << 7) != 0;
+                if lcd_en && !self.lcd_en {
+                    self.mode = Mode::OamSearch;
+                } else if !lcd_en && self.lcd_en {
+                    //turning the lcd off resets ly and leaves the ppu in hblank
+                    self.ly = 0;
+                    self.line_dots = 0;
+                    self.mode = Mode::HBlank;
+                }
+                self.lcd_en = lcd_en;
                 self.win_tile_area = value & (0x1 << 6) != 0;
                 self.win_en = value & (0x1 << 5) != 0;
                 self.bg_win_tile_area = value & (0x1 << 4) != 0;
@@ -68,7 +830,76 @@ impl ppu {
                 self.obj_en = value & (0x1 << 1) != 0;
                 self.bg_win_en = value & (0x1) != 0;
             }
+            0xFF41 => {
+                self.lyc_int = value & (0x1 << 6) != 0;
+                self.mode2_int = value & (0x1 << 5) != 0;
+                self.mode1_int = value & (0x1 << 4) != 0;
+                self.mode0_int = value & (0x1 << 3) != 0;
+                self.update_stat();
+            }
+            0xFF42 => self.scy = value,
+            0xFF43 => self.scx = value,
+            0xFF44 => {} //ly is read only
+            0xFF45 => {
+                self.lyc = value;
+                self.update_stat();
+            }
+            0xFF47 => self.bgp = value,
+            0xFF48 => self.obp[0] = value,
+            0xFF49 => self.obp[1] = value,
+            0xFF4A => self.wy = value,
+            0xFF4B => self.wx = value,
+            0xFF4F => {
+                if self.cgb_mode {
+                    self.cur_vram_bank = value & 0x01;
+                }
+            }
+            0xFF68..=0xFF6B if !self.cgb_mode => {}
+            0xFF68 => self.bcps = value & 0xBF,
+            0xFF69 => {
+                if self.mode != Mode::PixelTransfer {
+                    self.bg_palettes[self.bcps as usize & 0x3F] = value;
+                }
+                self.bcps = ppu::increment_palette_index(self.bcps);
+            }
+            0xFF6A => self.ocps = value & 0xBF,
+            0xFF6B => {
+                if self.mode != Mode::PixelTransfer {
+                    self.obj_palettes[self.ocps as usize & 0x3F] = value;
+                }
+                self.ocps = ppu::increment_palette_index(self.ocps);
+            }
             _ => panic!("Not a valid ppu memory area"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fifo_object_size_change_mid_line() {
+        let mut ppu = ppu::new();
+        ppu.set_renderer(Renderer::Fifo);
+        //tile 0 only has its row 3 set
+        ppu.write_byte(0x8006, 0xFF);
+        ppu.write_byte(0x8007, 0xFF);
+        ppu.write_byte(0xFF48, 0xE4);
+        //y flipped object whose rows 12-15 are on lines 0-3 in 8x16 mode
+        for (i, value) in [4, 80, 0, 0x40].iter().enumerate() {
+            ppu.write_byte(0xFE00 + i as u16, *value);
+        }
+        ppu.write_byte(0xFF40, 0x87);
+        //switch to 8x8 after the object was selected, before it is fetched
+        ppu.step(80 + 8);
+        ppu.write_byte(0xFF40, 0x83);
+        ppu.step(456 * SCREEN_HEIGHT as u32);
+
+        //row 12 wraps to row 4, flipped it shows row 3
+        let line = &ppu.frame()[..SCREEN_WIDTH];
+        assert!(line[72..80].iter().all(|&pixel| pixel == 0x000000));
+        assert!(line[..72].iter().all(|&pixel| pixel == 0xFFFFFF));
+        assert!(line[80..].iter().all(|&pixel| pixel == 0xFFFFFF));
+    }
+}
@@ -13,6 +13,7 @@ pub struct RegisterFile {
 }
 pub enum Registers8b {
     A,
+    #[allow(dead_code)] //F is only written through AF
     F,
     B,
     C,
@@ -30,10 +31,10 @@ pub enum Registers16b {
 }
 
 pub enum Flags {
-    FZ = 0b1000000,
-    FN = 0b0100000,
-    FH = 0b0010000,
-    FC = 0b0001000,
+    FZ = 0b10000000,
+    FN = 0b01000000,
+    FH = 0b00100000,
+    FC = 0b00010000,
     //4 lsb are not used
 }
 
@@ -115,17 +116,17 @@ impl RegisterFile {
 
     pub fn set_flag(&mut self, flag: Flags, set: bool) {
         if set {
-            self.f = self.f | flag as u8;
+            self.f |= flag as u8;
         } else {
-            self.f = self.f & !(flag as u8);
+            self.f &= !(flag as u8);
         }
     }
 
     pub fn set_flags(&mut self, f1: bool, f2: bool, f3: bool, f4: bool) {
         self.set_flag(Flags::FZ, f1);
-        self.set_flag(Flags::FN, f1);
-        self.set_flag(Flags::FH, f1);
-        self.set_flag(Flags::FC, f1);
+        self.set_flag(Flags::FN, f2);
+        self.set_flag(Flags::FH, f3);
+        self.set_flag(Flags::FC, f4);
     }
 
     pub fn get_flag(&self, flag: Flags) -> bool {
@@ -0,0 +1,225 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+//The apu runs at the dmg's T-cycle rate in both speed modes
+pub(crate) const CLOCK_RATE: u64 = 4_194_304;
+//Width of the band-limited impulse in output samples and its number of sub-sample phases
+const TAPS: usize = 32;
+const PHASES: usize = 64;
+//Cutoff as a fraction of the output nyquist frequency, leaves room for the kernel's rolloff
+const CUTOFF: f64 = 0.9;
+
+//Per T-cycle charge factors of the output capacitors, the cgb's is smaller and filters more
+const DMG_CHARGE: f64 = 0.999958;
+const CGB_CHARGE: f64 = 0.998943;
+
+//Resamples a stereo signal that only changes at T-cycle boundaries to the host rate. Every
+//change is added to the output as a band-limited step, which avoids the aliasing of taking
+//every nth value. All timing is in integers so the same input always gives the same samples.
+pub struct Resampler {
+    sample_rate: u64,
+    kernel: Vec<[f64; TAPS]>, //band-limited impulse for every phase, each sums to 1
+    time: u64,                //T-cycles since the start of the current second
+    second: u64,              //output samples before the current second
+    level: (f32, f32),        //current input
+    pending: VecDeque<[f64; 2]>, //deltas of the samples from next on
+    next: u64,                //index of the next output sample
+    sum: [f64; 2],            //integrated deltas, the unfiltered output
+    charge: f64,              //capacitor charge factor per output sample
+    capacitor: [f64; 2],
+    samples: Vec<[f32; 2]>,
+}
+
+impl Resampler {
+    pub fn new(sample_rate: u32, cgb: bool) -> Resampler {
+        let mut kernel = vec![[0.0; TAPS]; PHASES];
+        for (phase, taps) in kernel.iter_mut().enumerate() {
+            let offset = phase as f64 / PHASES as f64;
+            for (i, tap) in taps.iter_mut().enumerate() {
+                //distance from the impulse, which sits in the middle of the taps
+                let x = i as f64 - (TAPS / 2) as f64 - offset;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * CUTOFF * x).sin() / (PI * CUTOFF * x)
+                };
+                //blackman window
+                let w = 2.0 * PI * (x / TAPS as f64 + 0.5);
+                let window = 0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+                *tap = sinc * window;
+            }
+            let total: f64 = taps.iter().sum();
+            for tap in taps.iter_mut() {
+                *tap /= total;
+            }
+        }
+        let mut resampler = Resampler {
+            sample_rate: sample_rate as u64,
+            kernel,
+            time: 0,
+            second: 0,
+            level: (0.0, 0.0),
+            pending: VecDeque::new(),
+            next: 0,
+            sum: [0.0; 2],
+            charge: 0.0,
+            capacitor: [0.0; 2],
+            samples: Vec::new(),
+        };
+        resampler.set_cgb(cgb);
+        resampler
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate as u32
+    }
+
+    pub fn set_cgb(&mut self, cgb: bool) {
+        let charge = if cgb { CGB_CHARGE } else { DMG_CHARGE };
+        self.charge = charge.powf(CLOCK_RATE as f64 / self.sample_rate as f64);
+    }
+
+    //Feeds the input level for the next T-cycles
+    pub fn step(&mut self, ticks: u32, level: (f32, f32)) {
+        if level != self.level {
+            let delta = [
+                (level.0 - self.level.0) as f64,
+                (level.1 - self.level.1) as f64,
+            ];
+            self.add_delta(delta);
+            self.level = level;
+        }
+        self.time += ticks as u64;
+        if self.time >= CLOCK_RATE {
+            self.time -= CLOCK_RATE;
+            self.second += self.sample_rate;
+        }
+        self.finish_samples();
+    }
+
+    fn position(&self) -> (u64, usize) {
+        let scaled = self.time * self.sample_rate;
+        let index = self.second + scaled / CLOCK_RATE;
+        let phase = (scaled % CLOCK_RATE * PHASES as u64 / CLOCK_RATE) as usize;
+        (index, phase)
+    }
+
+    fn add_delta(&mut self, delta: [f64; 2]) {
+        let (index, phase) = self.position();
+        let start = (index - self.next) as usize;
+        if self.pending.len() < start + TAPS {
+            self.pending.resize(start + TAPS, [0.0; 2]);
+        }
+        for (i, tap) in self.kernel[phase].iter().enumerate() {
+            let sample = &mut self.pending[start + i];
+            sample[0] += delta[0] * tap;
+            sample[1] += delta[1] * tap;
+        }
+    }
+
+    //Samples far enough behind the current time can't receive any more deltas
+    fn finish_samples(&mut self) {
+        let (index, _) = self.position();
+        while self.next + TAPS as u64 <= index {
+            let delta = self.pending.pop_front().unwrap_or([0.0; 2]);
+            let mut sample = [0.0; 2];
+            for c in 0..2 {
+                self.sum[c] += delta[c];
+                sample[c] = self.high_pass(c, self.sum[c]) as f32;
+            }
+            self.samples.push(sample);
+            self.next += 1;
+        }
+    }
+
+    //Removes the dc offset the way the coupling capacitors on the audio output do
+    fn high_pass(&mut self, c: usize, input: f64) -> f64 {
+        let output = input - self.capacitor[c];
+        self.capacitor[c] = input - output * self.charge;
+        output
+    }
+
+    //Takes the finished samples as interleaved left and right values in -1.0..1.0
+    #[allow(dead_code)] //only reached through Apu::take_samples_f32 and the tests
+    pub fn take_f32(&mut self) -> Vec<f32> {
+        self.samples.drain(..).flat_map(|s| s.to_vec()).collect()
+    }
+
+    //Takes the finished samples as interleaved left and right 16 bit values
+    pub fn take_i16(&mut self) -> Vec<i16> {
+        self.samples
+            .drain(..)
+            .flat_map(|s| s.to_vec())
+            .map(|s| (s * 32767.0).round().clamp(-32768.0, 32767.0) as i16)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //64 T-cycles per output sample
+    const RATE: u32 = 65536;
+
+    //Square wave with a period of 4 output samples, offset by a quarter sample, panned left
+    fn square(cgb: bool, samples: u32) -> Resampler {
+        let mut resampler = Resampler::new(RATE, cgb);
+        resampler.step(16, (0.0, 0.0));
+        for i in 0..samples * 2 {
+            let level = if i % 2 == 0 { 0.5 } else { -0.5 };
+            resampler.step(128, (level, 0.0));
+        }
+        resampler
+    }
+
+    #[test]
+    fn band_limited_square() {
+        let samples = square(false, 48).take_i16();
+        let left: Vec<i16> = samples.iter().step_by(2).copied().collect();
+        assert_eq!(
+            left[..24],
+            [
+                0, 0, 2, 0, -6, 16, -28, 43, -57, 62, -50, 15, 54, -178, 431, -1213, 11548, 20594,
+                -8514, -21768, 9078, 21234, -8861, -21512
+            ]
+        );
+        assert!(samples.iter().skip(1).step_by(2).all(|&right| right == 0));
+    }
+
+    #[test]
+    fn output_does_not_depend_on_step_size() {
+        let mut whole = square(true, 20);
+        let mut ticks = Resampler::new(RATE, true);
+        ticks.step(16, (0.0, 0.0));
+        for i in 0..40 {
+            let level = if i % 2 == 0 { 0.5 } else { -0.5 };
+            for _ in 0..128 {
+                ticks.step(1, (level, 0.0));
+            }
+        }
+        let samples = whole.take_f32();
+        //16 + 40 * 128 T-cycles are 80.25 samples, the last TAPS are still open
+        assert_eq!(samples.len(), (80 - TAPS + 1) * 2);
+        assert_eq!(samples, ticks.take_f32());
+    }
+
+    //A constant input decays by the capacitor's charge factor every sample
+    fn decay(cgb: bool) -> f64 {
+        let mut resampler = Resampler::new(RATE, cgb);
+        for _ in 0..200 {
+            resampler.step(64, (1.0, 1.0));
+        }
+        let samples = resampler.take_f32();
+        samples[300] as f64 / samples[298] as f64
+    }
+
+    #[test]
+    fn dc_decay() {
+        let dmg = DMG_CHARGE.powf(64.0);
+        let cgb = CGB_CHARGE.powf(64.0);
+        assert!((decay(false) - dmg).abs() < 1e-6);
+        assert!((decay(true) - cgb).abs() < 1e-6);
+        assert!(cgb < dmg);
+    }
+}
@@ -0,0 +1,323 @@
+#[cfg(test)]
+use std::cell::Cell;
+#[cfg(test)]
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const RTC_SAVE_SIZE: usize = 48;
+
+const SECONDS_PER_DAY: u64 = 86400;
+
+//Time source for the rtc, in whole seconds since an arbitrary but fixed epoch
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+//Clock that only moves when told to, clones share the same time
+#[cfg(test)]
+#[derive(Clone)]
+pub struct ManualClock {
+    seconds: Rc<Cell<u64>>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new(start: u64) -> ManualClock {
+        ManualClock {
+            seconds: Rc::new(Cell::new(start)),
+        }
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        self.seconds.set(self.seconds.get() + seconds);
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.seconds.get()
+    }
+}
+
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
+struct RtcRegisters {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16, //9 bit day counter
+    halt: bool,
+    carry: bool,
+}
+
+impl RtcRegisters {
+    fn read(&self, reg: u8) -> u8 {
+        match reg {
+            0x08 => self.seconds & 0x3F,
+            0x09 => self.minutes & 0x3F,
+            0x0A => self.hours & 0x1F,
+            0x0B => (self.days & 0xFF) as u8,
+            0x0C => {
+                let mut byte = (self.days >> 8) as u8 & 0x01;
+                byte |= if self.halt { 0x1 << 6 } else { 0 };
+                byte |= if self.carry { 0x1 << 7 } else { 0 };
+                byte
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, reg: u8, value: u8) {
+        match reg {
+            0x08 => self.seconds = value & 0x3F,
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | value as u16,
+            0x0C => {
+                self.days = (self.days & 0xFF) | ((value as u16 & 0x01) << 8);
+                self.halt = value & (0x1 << 6) != 0;
+                self.carry = value & (0x1 << 7) != 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn in_range(&self) -> bool {
+        self.seconds < 60 && self.minutes < 60 && self.hours < 24
+    }
+
+    //single step of the counter chain, out of range values count up to their bit width and wrap without carry
+    fn tick(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.add_days(1);
+    }
+
+    fn add_days(&mut self, days: u64) {
+        let days = self.days as u64 + days;
+        if days > 0x1FF {
+            self.carry = true;
+        }
+        self.days = (days & 0x1FF) as u16;
+    }
+
+    fn advance(&mut self, mut seconds: u64) {
+        while seconds > 0 && !self.in_range() {
+            self.tick();
+            seconds -= 1;
+        }
+        let total =
+            self.seconds as u64 + self.minutes as u64 * 60 + self.hours as u64 * 3600 + seconds;
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+        self.add_days(total / SECONDS_PER_DAY);
+    }
+}
+
+pub struct Rtc {
+    clock: Box<dyn Clock>,
+    last_update: u64,
+    regs: RtcRegisters,
+    latched: RtcRegisters,
+    latch_armed: bool,
+}
+
+impl Rtc {
+    pub fn new(clock: Box<dyn Clock>) -> Rtc {
+        let last_update = clock.now();
+        Rtc {
+            clock,
+            last_update,
+            regs: RtcRegisters::default(),
+            latched: RtcRegisters::default(),
+            latch_armed: false,
+        }
+    }
+
+    fn update(&mut self) {
+        let now = self.clock.now();
+        if !self.regs.halt {
+            self.regs.advance(now.saturating_sub(self.last_update));
+        }
+        self.last_update = now;
+    }
+
+    pub fn read_byte(&self, reg: u8) -> u8 {
+        self.latched.read(reg)
+    }
+
+    //Returns true if the write changed the counters
+    pub fn write_byte(&mut self, reg: u8, value: u8) -> bool {
+        self.update();
+        let old = self.regs;
+        self.regs.write(reg, value);
+        self.regs != old
+    }
+
+    //writing 0x00 followed by 0x01 copies the running counters into the readable registers
+    pub fn write_latch(&mut self, value: u8) {
+        if self.latch_armed && value == 0x01 {
+            self.update();
+            self.latched = self.regs;
+        }
+        self.latch_armed = value == 0x00;
+    }
+
+    //Common 48 byte format: current and latched s/m/h/dl/dh as little endian u32s followed by a u64 timestamp
+    pub fn save(&self) -> [u8; RTC_SAVE_SIZE] {
+        let now = self.clock.now();
+        let mut regs = self.regs;
+        if !regs.halt {
+            regs.advance(now.saturating_sub(self.last_update));
+        }
+        let mut data = [0; RTC_SAVE_SIZE];
+        for (i, reg) in (0x08..=0x0C).enumerate() {
+            data[i * 4] = regs.read(reg);
+            data[20 + i * 4] = self.latched.read(reg);
+        }
+        data[40..48].copy_from_slice(&now.to_le_bytes());
+        data
+    }
+
+    pub fn load(&mut self, data: &[u8]) {
+        if data.len() < RTC_SAVE_SIZE - 4 {
+            return;
+        }
+        for (i, reg) in (0x08..=0x0C).enumerate() {
+            self.regs.write(reg, data[i * 4]);
+            self.latched.write(reg, data[20 + i * 4]);
+        }
+        //some emulators only store a 32 bit timestamp
+        let mut timestamp = [0; 8];
+        let len = (data.len() - 40).min(8);
+        timestamp[..len].copy_from_slice(&data[40..40 + len]);
+        self.last_update = u64::from_le_bytes(timestamp);
+        self.update();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rtc(start: u64) -> (Rtc, ManualClock) {
+        let clock = ManualClock::new(start);
+        (Rtc::new(Box::new(clock.clone())), clock)
+    }
+
+    fn latch(rtc: &mut Rtc) {
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+    }
+
+    #[test]
+    fn latched_registers_only_change_on_latch() {
+        let (mut rtc, clock) = rtc(1000);
+        clock.advance(3661);
+        assert_eq!(rtc.read_byte(0x08), 0);
+        latch(&mut rtc);
+        assert_eq!(rtc.read_byte(0x08), 1);
+        assert_eq!(rtc.read_byte(0x09), 1);
+        assert_eq!(rtc.read_byte(0x0A), 1);
+        clock.advance(5);
+        assert_eq!(rtc.read_byte(0x08), 1);
+        //0x01 without a preceding 0x00 does not latch
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.read_byte(0x08), 1);
+        latch(&mut rtc);
+        assert_eq!(rtc.read_byte(0x08), 6);
+    }
+
+    #[test]
+    fn halt_stops_the_clock() {
+        let (mut rtc, clock) = rtc(0);
+        clock.advance(10);
+        rtc.write_byte(0x0C, 0x40);
+        clock.advance(100);
+        latch(&mut rtc);
+        assert_eq!(rtc.read_byte(0x08), 10);
+        assert_eq!(rtc.read_byte(0x0C), 0x40);
+        rtc.write_byte(0x0C, 0x00);
+        clock.advance(5);
+        latch(&mut rtc);
+        assert_eq!(rtc.read_byte(0x08), 15);
+    }
+
+    #[test]
+    fn day_counter_rolls_over_and_sets_carry() {
+        let (mut rtc, clock) = rtc(0);
+        rtc.write_byte(0x0B, 0xFF);
+        rtc.write_byte(0x0C, 0x00);
+        clock.advance(SECONDS_PER_DAY);
+        latch(&mut rtc);
+        assert_eq!(rtc.read_byte(0x0B), 0x00);
+        assert_eq!(rtc.read_byte(0x0C), 0x01);
+        clock.advance(SECONDS_PER_DAY * 0x100);
+        latch(&mut rtc);
+        assert_eq!(rtc.read_byte(0x0B), 0x00);
+        assert_eq!(rtc.read_byte(0x0C), 0x80);
+        //the carry stays set until cleared by a write
+        clock.advance(SECONDS_PER_DAY);
+        latch(&mut rtc);
+        assert_eq!(rtc.read_byte(0x0B), 0x01);
+        assert_eq!(rtc.read_byte(0x0C), 0x80);
+        rtc.write_byte(0x0C, 0x00);
+        latch(&mut rtc);
+        assert_eq!(rtc.read_byte(0x0C), 0x00);
+    }
+
+    #[test]
+    fn save_round_trip() {
+        let (mut rtc, clock) = rtc(5000);
+        clock.advance(2 * SECONDS_PER_DAY + 3723);
+        latch(&mut rtc);
+        clock.advance(7);
+        let data = rtc.save();
+        assert_eq!(data.len(), RTC_SAVE_SIZE);
+        assert_eq!(
+            data[0..20],
+            [10, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            data[20..40],
+            [3, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(data[40..48], clock.now().to_le_bytes());
+
+        //time that passes while the emulator is closed is caught up on load
+        clock.advance(50);
+        let mut loaded = Rtc::new(Box::new(clock.clone()));
+        loaded.load(&data);
+        assert_eq!(loaded.read_byte(0x08), 3);
+        latch(&mut loaded);
+        assert_eq!(loaded.read_byte(0x08), 0);
+        assert_eq!(loaded.read_byte(0x09), 3);
+        assert_eq!(loaded.read_byte(0x0B), 2);
+        assert_eq!(loaded.save()[0..20], rtc.save()[0..20]);
+    }
+}
@@ -0,0 +1,906 @@
+use crate::memory::Model;
+use crate::resample::Resampler;
+
+//Waveforms of the four pulse duty cycles, 12.5%, 25%, 50% and 75%
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+//Counts down to silence the channel, clocked at 256 Hz by the frame sequencer
+struct Length {
+    counter: u16,
+    max: u16,
+    enabled: bool,
+}
+
+impl Length {
+    fn new(max: u16) -> Length {
+        Length {
+            counter: 0,
+            max,
+            enabled: false,
+        }
+    }
+
+    fn load(&mut self, value: u8) {
+        self.counter = self.max - (value as u16 & (self.max - 1));
+    }
+
+    //Returns true if the counter ran out and the channel has to be turned off
+    fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+
+    //Enabling the counter in the half of the sequencer period that does not clock it still clocks
+    //it once. Returns true if the channel has to be turned off
+    fn write_enable(&mut self, enabled: bool, trigger: bool, first_half: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = enabled;
+        let mut disable = false;
+        if first_half && enabled && !was_enabled && self.counter > 0 {
+            self.counter -= 1;
+            disable = self.counter == 0 && !trigger;
+        }
+        if trigger && self.counter == 0 {
+            self.counter = if first_half && enabled {
+                self.max - 1
+            } else {
+                self.max
+            };
+        }
+        disable
+    }
+}
+
+//Volume envelope, clocked at 64 Hz by the frame sequencer
+struct Envelope {
+    initial: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            initial: 0,
+            increase: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    fn read(&self) -> u8 {
+        let mut byte = self.initial << 4 | self.period;
+        byte |= if self.increase { 0x1 << 3 } else { 0 };
+        byte
+    }
+
+    fn write(&mut self, value: u8) {
+        self.initial = value >> 4;
+        self.increase = value & (0x1 << 3) != 0;
+        self.period = value & 0x07;
+    }
+
+    //The dac is powered as long as the upper 5 bits of NRx2 are not all zero
+    fn dac_enabled(&self) -> bool {
+        self.initial != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period;
+        if self.increase && self.volume < 15 {
+            self.volume += 1;
+        } else if !self.increase && self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+}
+
+//Frequency sweep of channel 1, clocked at 128 Hz by the frame sequencer
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    enabled: bool,
+    shadow: u16,
+    timer: u8,
+    negate_used: bool, //a calculation in negate mode happened since the last trigger
+}
+
+impl Sweep {
+    fn new() -> Sweep {
+        Sweep {
+            period: 0,
+            negate: false,
+            shift: 0,
+            enabled: false,
+            shadow: 0,
+            timer: 0,
+            negate_used: false,
+        }
+    }
+
+    fn read(&self) -> u8 {
+        let mut byte = 0x80 | self.period << 4 | self.shift;
+        byte |= if self.negate { 0x1 << 3 } else { 0 };
+        byte
+    }
+
+    //Returns true if the channel has to be turned off, clearing negate after it was used does that
+    fn write(&mut self, value: u8) -> bool {
+        self.period = (value >> 4) & 0x07;
+        self.negate = value & (0x1 << 3) != 0;
+        self.shift = value & 0x07;
+        self.negate_used && !self.negate
+    }
+
+    //a period of 0 reloads the timer with 8
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    //Next frequency, above 2047 the channel is turned off
+    fn calculate(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift;
+        if self.negate {
+            self.negate_used = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+
+    //Returns false if the overflow check turns the channel off
+    fn trigger(&mut self, freq: u16) -> bool {
+        self.shadow = freq;
+        self.reload_timer();
+        self.enabled = self.period != 0 || self.shift != 0;
+        self.negate_used = false;
+        self.shift == 0 || self.calculate() <= 2047
+    }
+
+    //Returns the new frequency if it changed and false if the channel has to be turned off
+    fn clock(&mut self) -> (Option<u16>, bool) {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return (None, true);
+        }
+        self.reload_timer();
+        if !self.enabled || self.period == 0 {
+            return (None, true);
+        }
+        let freq = self.calculate();
+        if freq > 2047 {
+            return (None, false);
+        }
+        if self.shift == 0 {
+            return (None, true);
+        }
+        self.shadow = freq;
+        //the new frequency is checked for overflow a second time but not written back
+        (Some(freq), self.calculate() <= 2047)
+    }
+}
+
+//Square wave channel, only channel 1 has a sweep unit
+struct Pulse {
+    enabled: bool,
+    duty: u8,
+    duty_pos: u8,
+    freq: u16,
+    timer: u32, //T-cycles until the next duty step
+    length: Length,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+}
+
+impl Pulse {
+    fn new(sweep: bool) -> Pulse {
+        Pulse {
+            enabled: false,
+            duty: 0,
+            duty_pos: 0,
+            freq: 0,
+            timer: 0,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            sweep: if sweep { Some(Sweep::new()) } else { None },
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.freq as u32) * 4
+    }
+
+    fn step(&mut self, ticks: u32) {
+        let mut ticks = ticks;
+        while ticks > 0 {
+            if self.timer > ticks {
+                self.timer -= ticks;
+                return;
+            }
+            ticks -= self.timer;
+            self.timer = self.period();
+            self.duty_pos = (self.duty_pos + 1) % 8;
+        }
+    }
+
+    //Digital output of the channel, 0-15
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_TABLE[self.duty as usize][self.duty_pos as usize] * self.envelope.volume
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        if let Some(sweep) = &mut self.sweep {
+            let (freq, keep) = sweep.clock();
+            if let Some(freq) = freq {
+                self.freq = freq;
+            }
+            if !keep {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = self.period();
+        self.envelope.trigger();
+        if let Some(sweep) = &mut self.sweep {
+            if !sweep.trigger(self.freq) {
+                self.enabled = false;
+            }
+        }
+    }
+
+    //Registers are NRx0 to NRx4, length and frequency low are write only
+    fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => match &self.sweep {
+                Some(sweep) => sweep.read(),
+                None => 0xFF,
+            },
+            1 => 0x3F | self.duty << 6,
+            2 => self.envelope.read(),
+            3 => 0xFF,
+            _ => {
+                let mut byte = 0xBF;
+                byte |= if self.length.enabled { 0x1 << 6 } else { 0 };
+                byte
+            }
+        }
+    }
+
+    fn write(&mut self, reg: u16, value: u8, first_half: bool) {
+        match reg {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    if sweep.write(value) {
+                        self.enabled = false;
+                    }
+                }
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(value);
+            }
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.freq = (self.freq & 0x700) | value as u16,
+            _ => {
+                self.freq = (self.freq & 0xFF) | (value as u16 & 0x07) << 8;
+                let trigger = value & (0x1 << 7) != 0;
+                if self
+                    .length
+                    .write_enable(value & (0x1 << 6) != 0, trigger, first_half)
+                {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+}
+
+//Plays 32 4 bit samples from wave ram, high nibble first
+struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    level: u8, //0 mutes, 1-3 shift the samples right by level - 1
+    freq: u16,
+    timer: u32,
+    position: u8,
+    sample: u8,      //last wave ram byte read by the channel
+    just_read: bool, //a sample was read during the last step
+    length: Length,
+    ram: [u8; 16],
+}
+
+impl Wave {
+    fn new() -> Wave {
+        Wave {
+            enabled: false,
+            dac_enabled: false,
+            level: 0,
+            freq: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            just_read: false,
+            length: Length::new(256),
+            ram: [0; 16],
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.freq as u32) * 2
+    }
+
+    fn step(&mut self, ticks: u32) {
+        self.just_read = false;
+        if !self.enabled {
+            return;
+        }
+        let mut ticks = ticks;
+        while ticks > 0 {
+            if self.timer > ticks {
+                self.timer -= ticks;
+                return;
+            }
+            ticks -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+            self.sample = self.ram[self.position as usize / 2];
+            self.just_read = true;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || self.level == 0 {
+            return 0;
+        }
+        let nibble = if self.position & 0x1 == 0 {
+            self.sample >> 4
+        } else {
+            self.sample & 0x0F
+        };
+        nibble >> (self.level - 1)
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    //On dmg retriggering right as a sample is read overwrites the start of wave ram with the
+    //byte, or the aligned 4 bytes, the channel was about to read
+    fn trigger(&mut self, cgb: bool) {
+        if !cgb && self.enabled && self.timer <= 2 {
+            let next = ((self.position as usize + 1) % 32) / 2;
+            if next < 4 {
+                self.ram[0] = self.ram[next];
+            } else {
+                let block = next & !0x03;
+                self.ram.copy_within(block..block + 4, 0);
+            }
+        }
+        self.enabled = self.dac_enabled;
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    //While playing, the cpu only reaches the byte the channel is reading, on dmg only in the
+    //cycle the channel reads it and otherwise sees 0xFF
+    fn read_ram(&self, index: usize, cgb: bool) -> u8 {
+        if !self.enabled {
+            return self.ram[index];
+        }
+        if cgb || self.just_read {
+            self.ram[self.position as usize / 2]
+        } else {
+            0xFF
+        }
+    }
+
+    fn write_ram(&mut self, index: usize, value: u8, cgb: bool) {
+        if !self.enabled {
+            self.ram[index] = value;
+        } else if cgb || self.just_read {
+            self.ram[self.position as usize / 2] = value;
+        }
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => {
+                let mut byte = 0x7F;
+                byte |= if self.dac_enabled { 0x1 << 7 } else { 0 };
+                byte
+            }
+            1 => 0xFF,
+            2 => 0x9F | self.level << 5,
+            3 => 0xFF,
+            _ => {
+                let mut byte = 0xBF;
+                byte |= if self.length.enabled { 0x1 << 6 } else { 0 };
+                byte
+            }
+        }
+    }
+
+    fn write(&mut self, reg: u16, value: u8, first_half: bool, cgb: bool) {
+        match reg {
+            0 => {
+                self.dac_enabled = value & (0x1 << 7) != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(value),
+            2 => self.level = (value >> 5) & 0x03,
+            3 => self.freq = (self.freq & 0x700) | value as u16,
+            _ => {
+                self.freq = (self.freq & 0xFF) | (value as u16 & 0x07) << 8;
+                let trigger = value & (0x1 << 7) != 0;
+                if self
+                    .length
+                    .write_enable(value & (0x1 << 6) != 0, trigger, first_half)
+                {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger(cgb);
+                }
+            }
+        }
+    }
+}
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+//Pseudo random noise from a 15 bit lfsr, optionally shortened to 7 bits
+struct Noise {
+    enabled: bool,
+    shift: u8,
+    width7: bool,
+    divisor: u8,
+    lfsr: u16,
+    timer: u32,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Noise {
+        Noise {
+            enabled: false,
+            shift: 0,
+            width7: false,
+            divisor: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor as usize] << self.shift
+    }
+
+    fn step(&mut self, ticks: u32) {
+        //shifts of 14 and 15 leave the lfsr without clocks
+        if !self.enabled || self.shift >= 14 {
+            return;
+        }
+        let mut ticks = ticks;
+        while ticks > 0 {
+            if self.timer > ticks {
+                self.timer -= ticks;
+                return;
+            }
+            ticks -= self.timer;
+            self.timer = self.period();
+            let bit = (self.lfsr ^ (self.lfsr >> 1)) & 0x1;
+            self.lfsr = (self.lfsr >> 1) | bit << 14;
+            if self.width7 {
+                self.lfsr = (self.lfsr & !(0x1 << 6)) | bit << 6;
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 0x1 != 0 {
+            return 0;
+        }
+        self.envelope.volume
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+        self.envelope.trigger();
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        match reg {
+            1 => 0xFF,
+            2 => self.envelope.read(),
+            3 => {
+                let mut byte = self.shift << 4 | self.divisor;
+                byte |= if self.width7 { 0x1 << 3 } else { 0 };
+                byte
+            }
+            _ => {
+                let mut byte = 0xBF;
+                byte |= if self.length.enabled { 0x1 << 6 } else { 0 };
+                byte
+            }
+        }
+    }
+
+    fn write(&mut self, reg: u16, value: u8, first_half: bool) {
+        match reg {
+            1 => self.length.load(value),
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.shift = value >> 4;
+                self.width7 = value & (0x1 << 3) != 0;
+                self.divisor = value & 0x07;
+            }
+            _ => {
+                let trigger = value & (0x1 << 7) != 0;
+                if self
+                    .length
+                    .write_enable(value & (0x1 << 6) != 0, trigger, first_half)
+                {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger();
+                }
+            }
+        }
+    }
+}
+
+//Converts a digital channel value to -1.0..1.0, a powered off dac outputs 0.0
+fn dac(value: u8, enabled: bool) -> f32 {
+    if enabled {
+        value as f32 / 7.5 - 1.0
+    } else {
+        0.0
+    }
+}
+
+pub struct Apu {
+    ch1: Pulse,
+    ch2: Pulse,
+    ch3: Wave,
+    ch4: Noise,
+    power: bool,                  //NR52 bit 7
+    nr50: u8,                     //master volume, bits 4-6 left and 0-2 right
+    nr51: u8,                     //panning, bits 4-7 send channels 1-4 left and bits 0-3 right
+    cgb: bool,                    //hardware model, decides the wave ram and power off quirks
+    frame_step: u8,               //next step of the frame sequencer, 0-7
+    last_div_bit: bool,           //the sequencer advances on the falling edge of DIV bit 4
+    resampler: Option<Resampler>, //host rate output, off until a sample rate is set
+    //every channel on its own before panning, for recording them separately
+    channel_resamplers: Vec<Resampler>,
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
+            ch1: Pulse::new(true),
+            ch2: Pulse::new(false),
+            ch3: Wave::new(),
+            ch4: Noise::new(),
+            power: true, //left on by the boot rom
+            nr50: 0,
+            nr51: 0,
+            cgb: false,
+            frame_step: 0,
+            last_div_bit: false,
+            resampler: None,
+            channel_resamplers: Vec::new(),
+        }
+    }
+
+    pub fn set_model(&mut self, model: Model) {
+        self.cgb = model == Model::Cgb;
+        if let Some(resampler) = &mut self.resampler {
+            resampler.set_cgb(self.cgb);
+        }
+        for resampler in &mut self.channel_resamplers {
+            resampler.set_cgb(self.cgb);
+        }
+    }
+
+    //Starts producing stereo samples at the given host rate, e.g. 44100 or 48000
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.resampler = Some(Resampler::new(sample_rate, self.cgb));
+    }
+
+    //Takes the samples produced since the last call, interleaved left and right
+    #[allow(dead_code)] //for frontends with an audio device, the cli only writes wav files
+    pub fn take_samples_f32(&mut self) -> Vec<f32> {
+        match &mut self.resampler {
+            Some(resampler) => resampler.take_f32(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn take_samples_i16(&mut self) -> Vec<i16> {
+        match &mut self.resampler {
+            Some(resampler) => resampler.take_i16(),
+            None => Vec::new(),
+        }
+    }
+
+    //Also resamples the dac output of every channel before panning and master volume, needs a
+    //sample rate to be set
+    pub fn set_channel_capture(&mut self, capture: bool) {
+        self.channel_resamplers.clear();
+        if let (true, Some(resampler)) = (capture, &self.resampler) {
+            for _ in 0..4 {
+                let sample_rate = resampler.sample_rate();
+                self.channel_resamplers
+                    .push(Resampler::new(sample_rate, self.cgb));
+            }
+        }
+    }
+
+    //Takes the mono samples of the given channel (1-4) produced since the last call
+    pub fn take_channel_samples_i16(&mut self, channel: usize) -> Vec<i16> {
+        match self.channel_resamplers.get_mut(channel - 1) {
+            Some(resampler) => resampler.take_i16().into_iter().step_by(2).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    //Advances the channels by the given number of T-cycles at normal speed. div_counter is the
+    //timer's system counter, in double speed DIV bit 5 drives the sequencer to keep it at 512 Hz
+    pub fn step(&mut self, ticks: u32, div_counter: u16, double_speed: bool) {
+        let bit = if double_speed { 13 } else { 12 };
+        let div_bit = div_counter & (0x1 << bit) != 0;
+        if self.power && self.last_div_bit && !div_bit {
+            self.clock_frame_sequencer();
+        }
+        self.last_div_bit = div_bit;
+
+        self.ch1.step(ticks);
+        self.ch2.step(ticks);
+        self.ch3.step(ticks);
+        self.ch4.step(ticks);
+
+        let output = self.output();
+        if let Some(resampler) = &mut self.resampler {
+            resampler.step(ticks, output);
+        }
+        if !self.channel_resamplers.is_empty() {
+            let dacs = [
+                self.channel_dac(1),
+                self.channel_dac(2),
+                self.channel_dac(3),
+                self.channel_dac(4),
+            ];
+            //half scale leaves headroom for the overshoot of the filter
+            for (resampler, dac) in self.channel_resamplers.iter_mut().zip(dacs) {
+                resampler.step(ticks, (dac / 2.0, dac / 2.0));
+            }
+        }
+    }
+
+    //Length at steps 0, 2, 4 and 6, sweep at 2 and 6 and the envelopes at 7
+    fn clock_frame_sequencer(&mut self) {
+        if self.frame_step & 0x1 == 0 {
+            self.ch1.clock_length();
+            self.ch2.clock_length();
+            self.ch3.clock_length();
+            self.ch4.clock_length();
+        }
+        match self.frame_step {
+            2 | 6 => self.ch1.clock_sweep(),
+            7 => {
+                self.ch1.envelope.clock();
+                self.ch2.envelope.clock();
+                self.ch4.envelope.clock();
+            }
+            _ => {}
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    //The next sequencer step does not clock the length counters
+    fn length_first_half(&self) -> bool {
+        self.frame_step % 2 == 1
+    }
+
+    //Turning the apu off clears all registers, wave ram and on dmg the length counters survive
+    fn power_off(&mut self) {
+        let lengths = [
+            self.ch1.length.counter,
+            self.ch2.length.counter,
+            self.ch3.length.counter,
+            self.ch4.length.counter,
+        ];
+        let ram = self.ch3.ram;
+        self.ch1 = Pulse::new(true);
+        self.ch2 = Pulse::new(false);
+        self.ch3 = Wave::new();
+        self.ch4 = Noise::new();
+        self.ch3.ram = ram;
+        if !self.cgb {
+            self.ch1.length.counter = lengths[0];
+            self.ch2.length.counter = lengths[1];
+            self.ch3.length.counter = lengths[2];
+            self.ch4.length.counter = lengths[3];
+        }
+        self.nr50 = 0;
+        self.nr51 = 0;
+    }
+
+    //Digital output of the given channel (1-4), 0-15
+    pub fn channel_output(&self, channel: usize) -> u8 {
+        match channel {
+            1 => self.ch1.output(),
+            2 => self.ch2.output(),
+            3 => self.ch3.output(),
+            4 => self.ch4.output(),
+            _ => 0,
+        }
+    }
+
+    //Dac output of the given channel (1-4) before panning and master volume
+    pub fn channel_dac(&self, channel: usize) -> f32 {
+        let enabled = match channel {
+            1 => self.ch1.dac_enabled(),
+            2 => self.ch2.dac_enabled(),
+            3 => self.ch3.dac_enabled,
+            4 => self.ch4.envelope.dac_enabled(),
+            _ => false,
+        };
+        dac(self.channel_output(channel), enabled)
+    }
+
+    //Left and right output in -1.0..1.0 after panning and master volume
+    pub fn output(&self) -> (f32, f32) {
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for channel in 1..=4 {
+            let value = self.channel_dac(channel);
+            if self.nr51 & (0x1 << (channel + 3)) != 0 {
+                left += value;
+            }
+            if self.nr51 & (0x1 << (channel - 1)) != 0 {
+                right += value;
+            }
+        }
+        let left_volume = ((self.nr50 >> 4) & 0x07) as f32 + 1.0;
+        let right_volume = (self.nr50 & 0x07) as f32 + 1.0;
+        (
+            left / 4.0 * left_volume / 8.0,
+            right / 4.0 * right_volume / 8.0,
+        )
+    }
+
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF10..=0xFF14 => self.ch1.read(addr - 0xFF10),
+            0xFF15 => 0xFF,
+            0xFF16..=0xFF19 => self.ch2.read(addr - 0xFF15),
+            0xFF1A..=0xFF1E => self.ch3.read(addr - 0xFF1A),
+            0xFF1F => 0xFF,
+            0xFF20..=0xFF23 => self.ch4.read(addr - 0xFF1F),
+            0xFF24 => self.nr50,
+            0xFF25 => self.nr51,
+            0xFF26 => {
+                let mut byte = 0x70;
+                byte |= if self.power { 0x1 << 7 } else { 0 };
+                byte |= if self.ch1.enabled { 0x1 } else { 0 };
+                byte |= if self.ch2.enabled { 0x1 << 1 } else { 0 };
+                byte |= if self.ch3.enabled { 0x1 << 2 } else { 0 };
+                byte |= if self.ch4.enabled { 0x1 << 3 } else { 0 };
+                byte
+            }
+            0xFF27..=0xFF2F => 0xFF,
+            0xFF30..=0xFF3F => self.ch3.read_ram(addr as usize - 0xFF30, self.cgb),
+            _ => panic!("Not a valid sound memory area"),
+        }
+    }
+
+    pub fn write_byte(&mut self, addr: u16, value: u8) {
+        //while powered off only NR52, wave ram and on dmg the length counters can be written
+        if !self.power && (0xFF10..=0xFF25).contains(&addr) {
+            if !self.cgb {
+                match addr {
+                    0xFF11 => self.ch1.length.load(value),
+                    0xFF16 => self.ch2.length.load(value),
+                    0xFF1B => self.ch3.length.load(value),
+                    0xFF20 => self.ch4.length.load(value),
+                    _ => {}
+                }
+            }
+            return;
+        }
+
+        let first_half = self.length_first_half();
+        match addr {
+            0xFF10..=0xFF14 => self.ch1.write(addr - 0xFF10, value, first_half),
+            0xFF15 => {}
+            0xFF16..=0xFF19 => self.ch2.write(addr - 0xFF15, value, first_half),
+            0xFF1A..=0xFF1E => self.ch3.write(addr - 0xFF1A, value, first_half, self.cgb),
+            0xFF1F => {}
+            0xFF20..=0xFF23 => self.ch4.write(addr - 0xFF1F, value, first_half),
+            0xFF24 => self.nr50 = value,
+            0xFF25 => self.nr51 = value,
+            0xFF26 => {
+                let power = value & (0x1 << 7) != 0;
+                if self.power && !power {
+                    self.power_off();
+                } else if !self.power && power {
+                    self.frame_step = 0;
+                }
+                self.power = power;
+            }
+            0xFF27..=0xFF2F => {}
+            0xFF30..=0xFF3F => self.ch3.write_ram(addr as usize - 0xFF30, value, self.cgb),
+            _ => panic!("Not a valid sound memory area"),
+        }
+    }
+}
@@ -0,0 +1,217 @@
+pub struct Timer {
+    counter: u16, //internal system counter, DIV is the upper byte
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    overflow: bool,  //tima overflowed last cycle, reload happens this cycle
+    reloading: bool, //tima was reloaded this cycle, writes to it are ignored
+    pub interrupt: bool,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            counter: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflow: false,
+            reloading: false,
+            interrupt: false,
+        }
+    }
+
+    pub fn counter(&self) -> u16 {
+        self.counter
+    }
+
+    //TIMA is clocked by the falling edge of the selected counter bit anded with the enable bit
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            _ => 7,
+        };
+        self.tac & 0x04 != 0 && self.counter & (0x1 << bit) != 0
+    }
+
+    fn increment_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        self.overflow = overflow;
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            self.tick();
+        }
+    }
+
+    //One M-cycle, the counter advances by 4 T-cycles
+    fn tick(&mut self) {
+        self.reloading = false;
+        if self.overflow {
+            //tima reads 0 for one cycle before being reloaded
+            self.overflow = false;
+            self.tima = self.tma;
+            self.reloading = true;
+            self.interrupt = true;
+        }
+        let old = self.signal();
+        self.counter = self.counter.wrapping_add(4);
+        if old && !self.signal() {
+            self.increment_tima();
+        }
+    }
+
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF04 => (self.counter >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => 0xF8 | self.tac,
+            _ => panic!("Not a valid timer memory area"),
+        }
+    }
+
+    pub fn write_byte(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF04 => {
+                //resetting the counter can produce a falling edge and increment tima
+                let old = self.signal();
+                self.counter = 0;
+                if old {
+                    self.increment_tima();
+                }
+            }
+            0xFF05 => {
+                if !self.reloading {
+                    self.tima = value;
+                    self.overflow = false;
+                }
+            }
+            0xFF06 => {
+                self.tma = value;
+                if self.reloading {
+                    self.tima = value;
+                }
+            }
+            0xFF07 => {
+                let old = self.signal();
+                self.tac = value & 0x07;
+                if old && !self.signal() {
+                    self.increment_tima();
+                }
+            }
+            _ => panic!("Not a valid timer memory area"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //TAC 0x05 clocks tima off counter bit 3, a falling edge every 4 M-cycles
+    fn running_timer() -> Timer {
+        let mut timer = Timer::new();
+        timer.write_byte(0xFF07, 0x05);
+        timer
+    }
+
+    //Runs until tima overflows, the reload has not happened yet
+    fn overflowed_timer() -> Timer {
+        let mut timer = running_timer();
+        timer.write_byte(0xFF06, 0xAB);
+        timer.write_byte(0xFF05, 0xFF);
+        timer.step(4);
+        assert_eq!(timer.read_byte(0xFF05), 0x00);
+        assert!(!timer.interrupt);
+        timer
+    }
+
+    #[test]
+    fn increments_on_falling_edge() {
+        let mut timer = running_timer();
+        timer.step(3);
+        assert_eq!(timer.read_byte(0xFF05), 0);
+        timer.step(1);
+        assert_eq!(timer.read_byte(0xFF05), 1);
+    }
+
+    #[test]
+    fn div_write_falling_edge() {
+        let mut timer = running_timer();
+        //bit 3 is high, resetting the counter pulls it low
+        timer.step(2);
+        timer.write_byte(0xFF04, 0x00);
+        assert_eq!(timer.read_byte(0xFF05), 1);
+        //bit 3 is low, no edge
+        timer.write_byte(0xFF04, 0x00);
+        assert_eq!(timer.read_byte(0xFF05), 1);
+        //the counter starts over, the next edge is 4 M-cycles away
+        timer.step(3);
+        assert_eq!(timer.read_byte(0xFF05), 1);
+        timer.step(1);
+        assert_eq!(timer.read_byte(0xFF05), 2);
+    }
+
+    #[test]
+    fn tac_write_falling_edge() {
+        let mut timer = running_timer();
+        timer.step(2);
+        //switching to bit 9, which is low, is a falling edge
+        timer.write_byte(0xFF07, 0x04);
+        assert_eq!(timer.read_byte(0xFF05), 1);
+        //back to bit 3 is a rising edge
+        timer.write_byte(0xFF07, 0x05);
+        assert_eq!(timer.read_byte(0xFF05), 1);
+        //disabling the timer while the bit is high is a falling edge as well
+        timer.write_byte(0xFF07, 0x01);
+        assert_eq!(timer.read_byte(0xFF05), 2);
+        assert_eq!(timer.read_byte(0xFF07), 0xF9);
+    }
+
+    #[test]
+    fn overflow_reloads_one_cycle_later() {
+        let mut timer = overflowed_timer();
+        timer.step(1);
+        assert_eq!(timer.read_byte(0xFF05), 0xAB);
+        assert!(timer.interrupt);
+    }
+
+    #[test]
+    fn tima_write_cancels_reload() {
+        let mut timer = overflowed_timer();
+        timer.write_byte(0xFF05, 0x42);
+        timer.step(1);
+        assert_eq!(timer.read_byte(0xFF05), 0x42);
+        assert!(!timer.interrupt);
+    }
+
+    #[test]
+    fn tima_write_during_reload_is_ignored() {
+        let mut timer = overflowed_timer();
+        timer.step(1);
+        timer.write_byte(0xFF05, 0x42);
+        assert_eq!(timer.read_byte(0xFF05), 0xAB);
+        //the cycle after that takes writes again
+        timer.step(1);
+        timer.write_byte(0xFF05, 0x42);
+        assert_eq!(timer.read_byte(0xFF05), 0x42);
+    }
+
+    #[test]
+    fn tma_write_during_reload_reaches_tima() {
+        let mut timer = overflowed_timer();
+        timer.step(1);
+        timer.write_byte(0xFF06, 0x33);
+        assert_eq!(timer.read_byte(0xFF05), 0x33);
+        assert_eq!(timer.read_byte(0xFF06), 0x33);
+        //outside the reload cycle only tma changes
+        timer.step(1);
+        timer.write_byte(0xFF06, 0x44);
+        assert_eq!(timer.read_byte(0xFF05), 0x33);
+    }
+}
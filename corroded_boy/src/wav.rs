@@ -0,0 +1,174 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::resample::CLOCK_RATE;
+use crate::sound::Apu;
+
+//T-cycles of one frame, 154 lines of 456 dots
+const FRAME_TICKS: u64 = 70224;
+
+//16 bit pcm wav file, the sizes in the header are filled in by finish
+pub struct WavWriter<W: Write + Seek> {
+    file: W,
+    channels: u16,
+    frames: u32, //written samples per channel
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create(
+        path: &Path,
+        sample_rate: u32,
+        channels: u16,
+    ) -> io::Result<WavWriter<BufWriter<File>>> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate, channels)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(file: W, sample_rate: u32, channels: u16) -> io::Result<WavWriter<W>> {
+        let mut writer = WavWriter {
+            file,
+            channels,
+            frames: 0,
+        };
+        let block_align = channels * 2;
+        let file = &mut writer.file;
+        file.write_all(b"RIFF")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+        file.write_all(b"fmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?; //pcm
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        Ok(writer)
+    }
+
+    //Samples are interleaved if the file has more than one channel
+    pub fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.frames += (samples.len() / self.channels as usize) as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        let data_size = self.frames * self.channels as u32 * 2;
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(36 + data_size).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&data_size.to_le_bytes())?;
+        self.file.flush()?;
+        Ok(self.file)
+    }
+}
+
+//Records the mixed apu output and optionally every channel on its own for a number of frames.
+//The channels go to mono files next to the mix, e.g. song_ch1.wav for song.wav
+pub struct Recorder {
+    mix: WavWriter<BufWriter<File>>,
+    channels: Vec<WavWriter<BufWriter<File>>>,
+    remaining: u64, //samples per channel still to write
+}
+
+impl Recorder {
+    pub fn start(
+        apu: &mut Apu,
+        path: &Path,
+        sample_rate: u32,
+        frames: u32,
+        separate_channels: bool,
+    ) -> io::Result<Recorder> {
+        apu.set_sample_rate(sample_rate);
+        apu.set_channel_capture(separate_channels);
+        let mix = WavWriter::create(path, sample_rate, 2)?;
+        let mut channels = Vec::new();
+        if separate_channels {
+            for channel in 1..=4 {
+                let channel_path = Recorder::channel_path(path, channel);
+                channels.push(WavWriter::create(&channel_path, sample_rate, 1)?);
+            }
+        }
+        Ok(Recorder {
+            mix,
+            channels,
+            remaining: frames as u64 * FRAME_TICKS * sample_rate as u64 / CLOCK_RATE,
+        })
+    }
+
+    fn channel_path(path: &Path, channel: usize) -> PathBuf {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        path.with_file_name(format!("{}_ch{}.wav", stem, channel))
+    }
+
+    //Writes the samples the apu produced since the last call, returns true once done
+    pub fn record(&mut self, apu: &mut Apu) -> io::Result<bool> {
+        let mix = apu.take_samples_i16();
+        let count = (mix.len() as u64 / 2).min(self.remaining) as usize;
+        self.mix.write(&mix[..count * 2])?;
+        for (i, writer) in self.channels.iter_mut().enumerate() {
+            let samples = apu.take_channel_samples_i16(i + 1);
+            writer.write(&samples[..count.min(samples.len())])?;
+        }
+        self.remaining -= count as u64;
+        Ok(self.remaining == 0)
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.mix.finish()?;
+        for writer in self.channels {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn header_and_sizes() {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), 48000, 2).unwrap();
+        writer.write(&[1, -1, 0x1234, -2]).unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(bytes[0..4], *b"RIFF");
+        assert_eq!(bytes[4..8], 44u32.to_le_bytes());
+        assert_eq!(bytes[8..16], *b"WAVEfmt ");
+        assert_eq!(bytes[16..20], 16u32.to_le_bytes());
+        assert_eq!(bytes[20..22], 1u16.to_le_bytes());
+        assert_eq!(bytes[22..24], 2u16.to_le_bytes());
+        assert_eq!(bytes[24..28], 48000u32.to_le_bytes());
+        assert_eq!(bytes[28..32], 192000u32.to_le_bytes());
+        assert_eq!(bytes[32..34], 4u16.to_le_bytes());
+        assert_eq!(bytes[34..36], 16u16.to_le_bytes());
+        assert_eq!(bytes[36..40], *b"data");
+        assert_eq!(bytes[40..44], 8u32.to_le_bytes());
+        assert_eq!(
+            bytes[44..],
+            [0x01, 0x00, 0xFF, 0xFF, 0x34, 0x12, 0xFE, 0xFF]
+        );
+    }
+
+    #[test]
+    fn empty_mono_file() {
+        let writer = WavWriter::new(Cursor::new(Vec::new()), 44100, 1).unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+        assert_eq!(bytes.len(), 44);
+        assert_eq!(bytes[4..8], 36u32.to_le_bytes());
+        assert_eq!(bytes[22..24], 1u16.to_le_bytes());
+        assert_eq!(bytes[28..32], 88200u32.to_le_bytes());
+        assert_eq!(bytes[32..34], 2u16.to_le_bytes());
+        assert_eq!(bytes[40..44], 0u32.to_le_bytes());
+    }
+}